@@ -0,0 +1,2407 @@
+// Copyright 2018 Serde Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::error::{Error, Result};
+use serde::de::value::SeqDeserializer;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess,
+    SeqAccess, Visitor,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+// Env vars are flat, but the serializer builds their names by joining the
+// nested field names with `_`. The key tree reverses this: every key is split
+// on `_` and each segment becomes one level of the tree, so all the keys
+// sharing a prefix end up grouped under the same node.
+#[derive(Debug, Default)]
+struct Node {
+    value: Option<String>,
+    // The 1-based line of the input the value was read from, 0 if unknown.
+    line: usize,
+    // With `repeat_keys_as_seq`, the values the key was set to before
+    // `value`, oldest first.
+    repeated: Vec<String>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn insert(&mut self, key: &str, value: String, line: usize) {
+        let node = self.entry(key);
+        node.value = Some(value);
+        node.line = line;
+    }
+
+    // Same as `insert`, keeping the earlier values of the key in `repeated`.
+    fn push(&mut self, key: &str, value: String, line: usize) {
+        let node = self.entry(key);
+        node.repeated.extend(node.value.replace(value));
+        node.line = line;
+    }
+
+    fn entry(&mut self, key: &str) -> &mut Node {
+        let segments = key.split('_').filter(|_| !key.is_empty());
+        self.entry_path(segments.map(str::to_string))
+    }
+
+    fn insert_path<I>(&mut self, segments: I, value: String, line: usize)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let node = self.entry_path(segments);
+        node.value = Some(value);
+        node.line = line;
+    }
+
+    fn entry_path<I>(&mut self, segments: I) -> &mut Node
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut node = self;
+        for segment in segments {
+            node = node.children.entry(segment).or_default();
+        }
+        node
+    }
+
+    fn get(&self, path: &[&str]) -> Option<&Node> {
+        path.iter()
+            .try_fold(self, |node, segment| node.children.get(*segment))
+    }
+}
+
+// Joins the physical lines ending with a `\` line continuation to the next
+// one. An even number of trailing `\` is only escaped backslashes. Each
+// logical line comes with the 1-based number of its first physical line.
+#[derive(Default)]
+struct LineJoiner {
+    current: Option<(usize, String)>,
+}
+
+impl LineJoiner {
+    // Takes the physical line `number`, giving back a logical line once it is
+    // complete.
+    fn push(&mut self, number: usize, line: &str) -> Option<(usize, String)> {
+        let (number, mut joined) =
+            self.current.take().unwrap_or((number, String::new()));
+        joined += line;
+        let backslashes = joined.chars().rev().take_while(|c| *c == '\\');
+        if backslashes.count() % 2 == 1 {
+            joined.pop();
+            self.current = Some((number, joined));
+            None
+        } else {
+            Some((number, joined))
+        }
+    }
+
+    // The last logical line, when the input ends with a continuation.
+    fn finish(self) -> Option<(usize, String)> {
+        self.current
+    }
+}
+
+fn logical_lines(input: &str) -> Vec<(usize, String)> {
+    let mut joiner = LineJoiner::default();
+    let mut lines: Vec<_> = input
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| joiner.push(i + 1, line))
+        .collect();
+    lines.extend(joiner.finish());
+    lines
+}
+
+// Splits `KEY1=v1 KEY2="v 2"` on the whitespace found outside of quotes.
+fn assignments(input: &str) -> Result<Vec<String>> {
+    let mut assignments = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if !current.is_empty() {
+                    assignments.push(std::mem::take(&mut current));
+                }
+                continue;
+            }
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            // A `\"` doesn't close a double quoted value.
+            (Some('"'), '\\') | (None, '\\') => {
+                current.push(c);
+                current.extend(chars.next());
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if quote.is_some() {
+        return Err(Error::Syntax);
+    }
+    if !current.is_empty() {
+        assignments.push(current);
+    }
+    Ok(assignments)
+}
+
+// Parse env vars into their keys, raw values and line numbers. Empty lines
+// and `#` comments are skipped.
+fn pairs(
+    input: &str,
+    options: &DeserializerOptions,
+) -> Result<Vec<(String, String, usize)>> {
+    let lines = if options.single_line {
+        let assignments = assignments(input).map_err(|err| err.at_line(1))?;
+        assignments.into_iter().map(|line| (1, line)).collect()
+    } else {
+        logical_lines(input)
+    };
+
+    let mut pairs = Vec::new();
+    for (number, line) in lines {
+        if let Some((key, value)) = pair(&line, number, options)? {
+            pairs.push((key, value, number));
+        }
+    }
+    Ok(pairs)
+}
+
+// Splits a logical line into its key and raw value, `None` for empty lines
+// and comments.
+fn pair(
+    line: &str,
+    number: usize,
+    options: &DeserializerOptions,
+) -> Result<Option<(String, String)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(options.comment_char) {
+        return Ok(None);
+    }
+    let (key, value) = line
+        .split_once(options.assignment.as_str())
+        .ok_or_else(|| Error::Syntax.at_line(number))?;
+    Ok(Some((key.trim().to_string(), value.to_string())))
+}
+
+// A leading UTF-8 byte order mark is ignored.
+const BOM: char = '\u{feff}';
+
+// Parse env vars into a key tree.
+fn parse(input: &str, options: &DeserializerOptions) -> Result<Node> {
+    let input = input.strip_prefix(BOM).unwrap_or(input);
+    if options.properties {
+        return Ok(parse_properties(input));
+    }
+    let mut root = Node::default();
+    for (key, value, number) in pairs(input, options)? {
+        if options.repeat_keys_as_seq {
+            root.push(&key, value, number);
+        } else {
+            root.insert(&key, value, number);
+        }
+    }
+    Ok(root)
+}
+
+// Parse a properties file into a key tree, its keys split on `.`.
+fn parse_properties(input: &str) -> Node {
+    let mut root = Node::default();
+    for (number, line) in logical_lines(input) {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with(['#', '!']) {
+            continue;
+        }
+        let (segments, value) = property(line);
+        root.insert_path(segments, value, number);
+    }
+    root
+}
+
+// Splits a properties line into the unescaped segments of its key and its
+// unescaped value. The key ends at the first unescaped `=`, `:` or space.
+fn property(line: &str) -> (Vec<String>, String) {
+    let mut segments = Vec::new();
+    let mut segment = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => segment.extend(chars.next().map(properties_unescape_char)),
+            '.' => segments.push(std::mem::take(&mut segment)),
+            '=' | ':' => break,
+            c if c.is_whitespace() => {
+                let rest = chars.as_str().trim_start();
+                chars = rest.strip_prefix(['=', ':']).unwrap_or(rest).chars();
+                break;
+            }
+            c => segment.push(c),
+        }
+    }
+    segments.push(segment);
+
+    let mut value = String::new();
+    let mut chars = chars.as_str().trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next().map(properties_unescape_char)),
+            c => value.push(c),
+        }
+    }
+    (segments, value)
+}
+
+// The character escaped by `\c` in a properties file.
+fn properties_unescape_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
+
+// One difference between two sets of env vars, with the raw values as
+// written in the input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyDiff {
+    Added {
+        key: String,
+        value: String,
+    },
+    Removed {
+        key: String,
+        value: String,
+    },
+    Changed {
+        key: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl KeyDiff {
+    // The key this difference is about.
+    pub fn key(&self) -> &str {
+        match self {
+            KeyDiff::Added { key, .. }
+            | KeyDiff::Removed { key, .. }
+            | KeyDiff::Changed { key, .. } => key,
+        }
+    }
+}
+
+// Compare two strings of env vars key by key, e.g. two versions of an env
+// file. The differences are sorted by key. A key set several times only
+// counts with its last value, as when sourcing.
+pub fn diff(a: &str, b: &str) -> Result<Vec<KeyDiff>> {
+    let options = DeserializerOptions::default();
+    let to_map = |input| -> Result<BTreeMap<String, String>> {
+        Ok(pairs(input, &options)?
+            .into_iter()
+            .map(|(key, value, _)| (key, value))
+            .collect())
+    };
+    let (a, mut b) = (to_map(a)?, to_map(b)?);
+
+    let mut diffs = Vec::new();
+    for (key, old) in a {
+        match b.remove(&key) {
+            None => diffs.push(KeyDiff::Removed { key, value: old }),
+            Some(new) if new != old => {
+                diffs.push(KeyDiff::Changed { key, old, new })
+            }
+            Some(_) => {}
+        }
+    }
+    diffs.extend(
+        b.into_iter()
+            .map(|(key, value)| KeyDiff::Added { key, value }),
+    );
+    diffs.sort_by(|x, y| x.key().cmp(y.key()));
+    Ok(diffs)
+}
+
+// Options changing how the input is read.
+#[derive(Debug)]
+pub struct DeserializerOptions {
+    // Read all the variables from a single line, separated by whitespace, as
+    // written by the serializer's `single_line` option.
+    pub single_line: bool,
+    // Lines starting with this character are comments, `#` by default.
+    pub comment_char: char,
+    // Deserialize sequences absent from the input as empty ones instead of
+    // erroring, on by default. Absent fields are skipped as usual, so that
+    // `#[serde(default)]` applies. Those serde finds missing are then handed
+    // to their type, which only succeeds for sequences, and the input is read
+    // again.
+    pub missing_seqs_as_empty: bool,
+    // Error with `Error::MissingKey` when a field other than an `Option` has
+    // no variable, sequences and fields with `#[serde(default)]` included.
+    pub require_all_fields: bool,
+    // The operator between keys and values, `=` by default. Match the
+    // serializer's `assignment` to read its output back.
+    pub assignment: String,
+    // Read a Java properties file, as written with `Format::DottedProperties`:
+    // keys are split on `.` instead of `_`, and matched against the field
+    // names as they are instead of uppercased. `single_line`, `comment_char`
+    // and `assignment` are ignored.
+    pub properties: bool,
+    // Collect the values of a key set on several lines, e.g. `TAG=a` and
+    // `TAG=b`, into a sequence, one element per line, instead of keeping the
+    // last one. Keys set once are read as usual.
+    pub repeat_keys_as_seq: bool,
+    // Record in errors the serde path they happened at, see `Error::path`.
+    pub track_path: bool,
+    // Read the flags written with the serializer's `presence_flag_suffix`:
+    // with `Some("SET")`, an `Option` whose key has `KEY_SET=false` is `None`,
+    // even if `KEY` is set too.
+    pub presence_flag_suffix: Option<String>,
+}
+
+impl Default for DeserializerOptions {
+    fn default() -> Self {
+        DeserializerOptions {
+            single_line: false,
+            comment_char: '#',
+            missing_seqs_as_empty: true,
+            require_all_fields: false,
+            assignment: String::from("="),
+            properties: false,
+            repeat_keys_as_seq: false,
+            track_path: false,
+            presence_flag_suffix: None,
+        }
+    }
+}
+
+// Deserialize an instance of type `T` from a string of env vars.
+pub fn from_str<T>(s: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    from_str_with_options(s, DeserializerOptions::default())
+}
+
+// Same as `from_str`, with the given `DeserializerOptions`.
+pub fn from_str_with_options<T>(
+    s: &str,
+    options: DeserializerOptions,
+) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    from_node(&parse(s, &options)?, &options)
+}
+
+// Same as `from_str`, reading the input line by line: only the key tree is
+// kept in memory, not the whole input.
+pub fn from_reader_streaming<R, T>(reader: R) -> Result<T>
+where
+    R: io::BufRead,
+    T: DeserializeOwned,
+{
+    let options = DeserializerOptions::default();
+    let mut root = Node::default();
+    let mut insert = |(number, line): (usize, String)| -> Result<()> {
+        if let Some((key, value)) = pair(&line, number, &options)? {
+            root.insert(&key, value, number);
+        }
+        Ok(())
+    };
+    let mut joiner = LineJoiner::default();
+    for (i, line) in reader.lines().enumerate() {
+        let mut line = line.map_err(|err| Error::from(err).at_line(i + 1))?;
+        if i == 0 && line.starts_with(BOM) {
+            line.remove(0);
+        }
+        if let Some(logical) = joiner.push(i + 1, &line) {
+            insert(logical)?;
+        }
+    }
+    if let Some(logical) = joiner.finish() {
+        insert(logical)?;
+    }
+    from_node(&root, &options)
+}
+
+// Deserialize one `T` from each of the documents of `s` separated by
+// `separator`, as written by `to_string_many`. Errors give the line in the
+// whole of `s`.
+pub fn from_str_many<T>(s: &str, separator: &str) -> Result<Vec<T>>
+where
+    T: DeserializeOwned,
+{
+    let mut offset = 0;
+    s.split(separator)
+        .map(|document| {
+            let result = from_str(document).map_err(|err| match err {
+                Error::Line(line, err) => Error::Line(line + offset, err),
+                err => err,
+            });
+            offset += document.matches('\n').count()
+                + separator.matches('\n').count();
+            result
+        })
+        .collect()
+}
+
+// Deserialize an instance of type `T` from already parsed keys and values, for
+// example from a filtered `std::env::vars()`. The values are taken as they
+// would be after sourcing: sequences don't need their outer single quotes.
+pub fn from_pairs<I, T>(pairs: I) -> Result<T>
+where
+    I: IntoIterator<Item = (String, String)>,
+    T: DeserializeOwned,
+{
+    let mut root = Node::default();
+    for (key, value) in pairs {
+        root.insert(&key, value, 0);
+    }
+    from_node(&root, &DeserializerOptions::default())
+}
+
+fn from_node<T>(root: &Node, options: &DeserializerOptions) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let absent = RefCell::new(BTreeSet::new());
+    loop {
+        let before = absent.borrow().len();
+        let result = T::deserialize(Deserializer {
+            node: root,
+            key: String::new(),
+            shadowed: Vec::new(),
+            missing: None,
+            absent: &absent,
+            options,
+        });
+        // Some fields serde found missing are handed out this time.
+        if result.is_err() && absent.borrow().len() > before {
+            continue;
+        }
+        return result;
+    }
+}
+
+// Stands for the fields absent from the input, see `missing_seqs_as_empty`.
+static MISSING: Node = Node {
+    value: None,
+    line: 0,
+    repeated: Vec::new(),
+    children: BTreeMap::new(),
+};
+
+// Walks the key tree: structs and maps look up their fields in the children of
+// the current node, scalars and sequences parse the value of the node itself.
+struct Deserializer<'a> {
+    node: &'a Node,
+    key: String,
+    // Paths below `node` that a sibling field has already claimed. With fields
+    // `nested` and `nested_again` side by side, `NESTED_AGAIN_*` belongs to
+    // `nested_again`, so `nested` must not look it up again.
+    shadowed: Vec<Vec<String>>,
+    // The name of the field, when it is absent from the input.
+    missing: Option<&'static str>,
+    // The keys of the absent fields to hand to their type instead of
+    // skipping them, see `missing_seqs_as_empty`. Shared by the whole input.
+    absent: &'a RefCell<BTreeSet<String>>,
+    options: &'a DeserializerOptions,
+}
+
+impl<'a> Deserializer<'a> {
+    fn child(&self, path: &[&str]) -> Result<Option<Deserializer<'a>>> {
+        let node = match self.node.get(path) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let key = self.child_key(path);
+
+        let mut shadowed = Vec::new();
+        for claimed in &self.shadowed {
+            if path.len() >= claimed.len()
+                && claimed.iter().zip(path).all(|(c, p)| c == p)
+            {
+                // The key could be this field's just as well as the sibling's
+                // that claimed it first: there is no way to tell them apart.
+                return Err(Error::AmbiguousKey(key));
+            }
+            if path.iter().zip(claimed).all(|(p, c)| p == c) {
+                shadowed.push(claimed[path.len()..].to_vec());
+            }
+        }
+        Ok(Some(Deserializer {
+            node,
+            key,
+            shadowed,
+            missing: None,
+            absent: self.absent,
+            options: self.options,
+        }))
+    }
+
+    // The key of the variable at `path` below the current node.
+    fn child_key(&self, path: &[&str]) -> String {
+        let mut key = self.key.clone();
+        for segment in path {
+            if !key.is_empty() {
+                key += if self.options.properties { "." } else { "_" };
+            }
+            key += segment;
+        }
+        key
+    }
+
+    fn missing(&self, field: &'static str, path: &[&str]) -> Deserializer<'a> {
+        Deserializer {
+            node: &MISSING,
+            key: self.child_key(path),
+            shadowed: Vec::new(),
+            missing: Some(field),
+            absent: self.absent,
+            options: self.options,
+        }
+    }
+
+    // Whether the `presence_flag_suffix` flag below the node is `false`.
+    fn flagged_absent(&self) -> bool {
+        let suffix = match &self.options.presence_flag_suffix {
+            Some(suffix) if self.options.properties => suffix.clone(),
+            Some(suffix) => suffix.to_uppercase(),
+            None => return false,
+        };
+        let flag = if self.options.properties {
+            self.node.get(&[suffix.as_str()])
+        } else {
+            self.node.get(&suffix.split('_').collect::<Vec<_>>())
+        };
+        flag.and_then(|flag| flag.value.as_deref())
+            .is_some_and(|value| unquote(value) == "false")
+    }
+
+    // The key tree of a value holding a whole document, as written with the
+    // serializer's `nested_blocks`, for a node without children.
+    fn block(&self) -> Result<Option<Node>> {
+        if self.missing.is_some() || !self.node.children.is_empty() {
+            return Ok(None);
+        }
+        let value = match &self.node.value {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let body = value.strip_prefix("$'").and_then(|v| v.strip_suffix('\''));
+        let document = match body {
+            Some(body) => ansi_c_unescape(body)?,
+            None => unquote(value).to_string(),
+        };
+        if !document.contains('=') {
+            return Ok(None);
+        }
+        let line = self.node.line;
+        parse(&document, self.options)
+            .map(Some)
+            .map_err(|err| in_block(err, line))
+    }
+
+    // Deserializes the document of `block` in place of the node.
+    fn block_deserializer<'b>(&self, root: &'b Node) -> Deserializer<'b>
+    where
+        'a: 'b,
+    {
+        Deserializer {
+            node: root,
+            key: self.key.clone(),
+            shadowed: Vec::new(),
+            missing: None,
+            absent: self.absent,
+            options: self.options,
+        }
+    }
+
+    fn is_shadowed(&self, segment: &str) -> bool {
+        self.shadowed
+            .iter()
+            .any(|claimed| claimed.len() == 1 && claimed[0] == segment)
+    }
+
+    // The error for a required field absent from the input.
+    fn missing_error(&self, field: &'static str) -> Error {
+        if self.options.require_all_fields {
+            return Error::MissingKey(self.key.clone());
+        }
+        de::Error::missing_field(field)
+    }
+
+    fn value(&self) -> Result<ValueDeserializer<'a>> {
+        if let Some(field) = self.missing {
+            return Err(self.missing_error(field));
+        }
+        match &self.node.value {
+            Some(value) => Ok(ValueDeserializer {
+                value,
+                track_path: self.options.track_path,
+            }),
+            None => Err(Error::MissingValue(self.key.clone())),
+        }
+    }
+}
+
+// Errors from a block give the line of its variable, not the one inside the
+// block.
+fn in_block(err: Error, line: usize) -> Error {
+    match err {
+        Error::Line(_, err) => err.at_line(line),
+        err => err.at_line(line),
+    }
+}
+
+// Prepends `segment` to the path of `err` with `track_path`.
+fn in_path(err: Error, segment: &str, track_path: bool) -> Error {
+    if track_path {
+        err.in_path(segment)
+    } else {
+        err
+    }
+}
+
+fn to_segments(path: &[String]) -> Vec<&str> {
+    path.iter().map(String::as_str).collect()
+}
+
+macro_rules! forward_to_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                let line = self.node.line;
+                self.value()?
+                    .$method(visitor)
+                    .map_err(|err| err.at_line(line))
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if self.node.children.is_empty() {
+            let line = self.node.line;
+            self.value()?
+                .deserialize_any(visitor)
+                .map_err(|err| err.at_line(line))
+        } else {
+            self.deserialize_map(visitor)
+        }
+    }
+
+    forward_to_value! {
+        deserialize_bool
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_i128 deserialize_u128
+        deserialize_f32 deserialize_f64
+        deserialize_char deserialize_str deserialize_string
+        deserialize_bytes deserialize_byte_buf
+        deserialize_unit
+    }
+
+    // An absent sequence is an empty one, unless `require_all_fields` is set.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(field) = self.missing {
+            if self.options.require_all_fields {
+                return Err(self.missing_error(field));
+            }
+            let empty = std::iter::empty::<()>();
+            return visitor.visit_seq(SeqDeserializer::<_, Error>::new(empty));
+        }
+        let line = self.node.line;
+        if !self.node.repeated.is_empty() {
+            // Each line holds a single element.
+            let node = self.node;
+            let elements: Vec<&str> = node
+                .repeated
+                .iter()
+                .chain(&node.value)
+                .map(String::as_str)
+                .collect();
+            return visitor
+                .visit_seq(ValueSeqAccess {
+                    elements: elements.into_iter(),
+                    index: 0,
+                    track_path: self.options.track_path,
+                })
+                .map_err(|err| err.at_line(line));
+        }
+        self.value()?
+            .deserialize_seq(visitor)
+            .map_err(|err| err.at_line(line))
+    }
+
+    // Absent keys are only handed out by `StructAccess` with
+    // `missing_seqs_as_empty` or `require_all_fields`.
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if self.missing.is_some() || self.flagged_absent() {
+            return visitor.visit_none();
+        }
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    // Map keys are whatever the first segment of the remaining keys is. Maps
+    // don't expect any key in particular, so the ones claimed by a sibling
+    // field are simply left to it.
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(field) = self.missing {
+            return Err(self.missing_error(field));
+        }
+        if let Some(root) = self.block()? {
+            let line = self.node.line;
+            return self
+                .block_deserializer(&root)
+                .deserialize_map(visitor)
+                .map_err(|err| in_block(err, line));
+        }
+        let mut entries = Vec::new();
+        for segment in self.node.children.keys() {
+            if self.is_shadowed(segment) {
+                continue;
+            }
+            if let Some(child) = self.child(&[segment.as_str()])? {
+                entries.push((segment.clone(), child));
+            }
+        }
+        visitor.visit_map(MapNodeAccess {
+            entries: entries.into_iter(),
+            value: None,
+        })
+    }
+
+    // Each field name is uppercased and split on `_` the same way the keys
+    // were, then looked up as a path from the current node. This is what makes
+    // `NESTED_AGAIN_INT32` end up in the `nested_again` field: the field is
+    // found two levels down instead of one.
+    //
+    // When a field's path is a prefix of another field's path, the longer one
+    // wins and its keys are hidden from the shorter one. The shorter field
+    // only errors with `AmbiguousKey` if it actually asks for one of them.
+    //
+    // A struct written as a block with `nested_blocks` is parsed from its
+    // value first.
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(field) = self.missing {
+            return Err(self.missing_error(field));
+        }
+        if let Some(root) = self.block()? {
+            let line = self.node.line;
+            return self
+                .block_deserializer(&root)
+                .deserialize_struct(name, fields, visitor)
+                .map_err(|err| in_block(err, line));
+        }
+        let paths = fields
+            .iter()
+            .map(|field| {
+                if self.options.properties {
+                    return vec![field.to_string()];
+                }
+                field
+                    .to_uppercase()
+                    .split('_')
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut entries = Vec::new();
+        let mut skipped = Vec::new();
+        for (field, path) in fields.iter().zip(&paths) {
+            let mut child = match self.child(&to_segments(path))? {
+                Some(child) => child,
+                None => {
+                    let key = self.child_key(&to_segments(path));
+                    let hand_out = self.options.require_all_fields
+                        || self.options.missing_seqs_as_empty
+                            && self.absent.borrow().contains(&key);
+                    if hand_out {
+                        entries.push((
+                            *field,
+                            self.missing(field, &to_segments(path)),
+                        ));
+                    } else {
+                        skipped.push((*field, key));
+                    }
+                    continue;
+                }
+            };
+            for other in &paths {
+                if other.len() > path.len()
+                    && other.starts_with(path)
+                    && self.node.get(&to_segments(other)).is_some()
+                {
+                    child.shadowed.push(other[path.len()..].to_vec());
+                }
+            }
+            entries.push((*field, child));
+        }
+        let result = visitor.visit_map(StructAccess {
+            entries: entries.into_iter(),
+            value: None,
+        });
+        // A skipped field serde has no default for, maybe a sequence, is
+        // handed out when the input is read again.
+        if let Err(err) = &result {
+            if self.options.missing_seqs_as_empty {
+                for (field, key) in skipped {
+                    if *err == <Error as de::Error>::missing_field(field) {
+                        self.absent.borrow_mut().insert(key);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let line = self.node.line;
+        self.value()?
+            .deserialize_enum(name, variants, visitor)
+            .map_err(|err| err.at_line(line))
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+}
+
+struct StructAccess<'a> {
+    entries: std::vec::IntoIter<(&'static str, Deserializer<'a>)>,
+    value: Option<(&'static str, Deserializer<'a>)>,
+}
+
+impl<'de> MapAccess<'de> for StructAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.entries.next() {
+            Some((field, value)) => {
+                self.value = Some((field, value));
+                seed.deserialize(field.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some((key, value)) => {
+                let track_path = value.options.track_path;
+                seed.deserialize(value)
+                    .map_err(|err| in_path(err, key, track_path))
+            }
+            None => Err(de::Error::custom("value is missing")),
+        }
+    }
+}
+
+struct MapNodeAccess<'a> {
+    entries: std::vec::IntoIter<(String, Deserializer<'a>)>,
+    value: Option<(String, Deserializer<'a>)>,
+}
+
+impl<'de> MapAccess<'de> for MapNodeAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some((key.clone(), value));
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        match self.value.take() {
+            Some((key, value)) => {
+                let track_path = value.options.track_path;
+                seed.deserialize(value)
+                    .map_err(|err| in_path(err, &key, track_path))
+            }
+            None => Err(de::Error::custom("value is missing")),
+        }
+    }
+}
+
+// Parses the raw text on the right hand side of the `=`.
+struct ValueDeserializer<'a> {
+    value: &'a str,
+    // Prepend the index of sequence elements to the path of their errors.
+    track_path: bool,
+}
+
+// Every scalar goes through here before being parsed, so that `"true"`,
+// `'1.5'` and `text` are all read the same way whatever their type. Only one
+// matching pair of quotes is stripped.
+fn unquote(value: &str) -> &str {
+    let bytes = value.as_bytes();
+    match (bytes.first(), bytes.last()) {
+        (Some(b'"'), Some(b'"')) | (Some(b'\''), Some(b'\''))
+            if value.len() >= 2 =>
+        {
+            &value[1..value.len() - 1]
+        }
+        _ => value,
+    }
+}
+
+// Decodes the body of a `$'...'` ANSI-C quoted value, as bash does.
+fn ansi_c_unescape(body: &str) -> Result<String> {
+    let invalid = || Error::InvalidEscape(body.to_string());
+    let mut unescaped = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let c = chars.next().ok_or_else(invalid)?;
+        let decoded = match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\u{7}',
+            'b' => '\u{8}',
+            'e' | 'E' => '\u{1b}',
+            'f' => '\u{c}',
+            'v' => '\u{b}',
+            '\\' | '\'' | '"' | '?' => c,
+            'x' | 'u' | 'U' => {
+                let len = match c {
+                    'x' => 2,
+                    'u' => 4,
+                    _ => 8,
+                };
+                // Like bash, take up to `len` hex digits
+                let hex: String = chars
+                    .clone()
+                    .take(len)
+                    .take_while(char::is_ascii_hexdigit)
+                    .collect();
+                let code =
+                    u32::from_str_radix(&hex, 16).map_err(|_| invalid())?;
+                chars.nth(hex.len() - 1);
+                char::from_u32(code).ok_or_else(invalid)?
+            }
+            _ => return Err(invalid()),
+        };
+        unescaped.push(decoded);
+    }
+    Ok(unescaped)
+}
+
+impl<'a> ValueDeserializer<'a> {
+    fn unquoted(&self) -> &'a str {
+        unquote(self.value)
+    }
+
+    fn parse<T: std::str::FromStr>(
+        &self,
+        err: fn(String) -> Error,
+    ) -> Result<T> {
+        self.unquoted()
+            .parse()
+            .map_err(|_| err(self.value.to_string()))
+    }
+
+    // Sequences are serialized as `'elem,elem'`, where string elements are
+    // double quoted and may contain commas themselves. Once sourced, the outer
+    // single quotes are gone, so they are optional.
+    fn elements(&self) -> Result<Vec<&'a str>> {
+        let value = self.value;
+        let body = if value.len() >= 2
+            && value.starts_with('\'')
+            && value.ends_with('\'')
+        {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+        if body.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut elements = Vec::new();
+        let mut start = 0;
+        let mut in_quotes = false;
+        for (i, c) in body.char_indices() {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                ',' if !in_quotes => {
+                    elements.push(&body[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if in_quotes {
+            return Err(Error::MalformedSequence(value.to_string()));
+        }
+        elements.push(&body[start..]);
+        Ok(elements)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_bool(self.parse(Error::ParseBool)?)
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i8(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i16(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i32(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i64(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u8(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u16(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u32(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u64(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i128(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u128(self.parse(Error::ParseInt)?)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_f32(self.parse(Error::ParseFloat)?)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_f64(self.parse(Error::ParseFloat)?)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    // `$'...'` values are unescaped, see `ascii_escape_values`.
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let value = self.value;
+        if value.len() >= 3 && value.starts_with("$'") && value.ends_with('\'')
+        {
+            let body = &value[2..value.len() - 1];
+            return visitor.visit_string(ansi_c_unescape(body)?);
+        }
+        visitor.visit_str(self.unquoted())
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    // Bytes are serialized as a sequence of `u8`.
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let elements = self.elements()?;
+        visitor.visit_seq(ValueSeqAccess {
+            elements: elements.into_iter(),
+            index: 0,
+            track_path: self.track_path,
+        })
+    }
+
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V>(self, _visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::ExpectedMap)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        Err(Error::ExpectedMap)
+    }
+
+    // Only unit variants can be held by a single value.
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_enum(self.unquoted().into_deserializer())
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+}
+
+struct ValueSeqAccess<'a> {
+    elements: std::vec::IntoIter<&'a str>,
+    // The index of the next element.
+    index: usize,
+    track_path: bool,
+}
+
+impl<'de> SeqAccess<'de> for ValueSeqAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let value = match self.elements.next() {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let index = self.index.to_string();
+        self.index += 1;
+        let track_path = self.track_path;
+        seed.deserialize(ValueDeserializer { value, track_path })
+            .map(Some)
+            .map_err(|err| in_path(err, &index, track_path))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        diff, from_pairs, from_reader_streaming, from_str, from_str_many,
+        from_str_with_options, DeserializerOptions, KeyDiff,
+    };
+    use crate::error::Error;
+    use crate::ser::{
+        to_string, to_string_with_options, Format, SerializerOptions,
+    };
+    use serde_derive::{Deserialize, Serialize};
+
+    #[test]
+    fn test_struct() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            uint8: u8,
+            int64: i64,
+            float64: f64,
+            boolean: bool,
+            character: char,
+            string: String,
+        }
+
+        let test = Test {
+            uint8: 1,
+            int64: -1,
+            float64: 1.5,
+            boolean: true,
+            character: 'c',
+            string: String::from("s"),
+        };
+        let input = to_string(&test).unwrap();
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+    }
+
+    #[test]
+    fn test_seq() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            seq: Vec<String>,
+            ints: Vec<i32>,
+        }
+
+        let test = Test {
+            seq: vec![String::from("a,b"), String::from("c")],
+            ints: vec![1, 2],
+        };
+        let input = to_string(&test).unwrap();
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+    }
+
+    #[test]
+    fn test_negative_seq() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            seq: Vec<i64>,
+        }
+
+        let test = Test {
+            seq: vec![-1, 2, -3],
+        };
+        let deserialized: Test = from_str(&to_string(&test).unwrap()).unwrap();
+        assert_eq!(deserialized, test);
+    }
+
+    #[test]
+    fn test_integer_bounds() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            min: i64,
+            umax: u64,
+            seq: Vec<i64>,
+            int128: Vec<i128>,
+            uint128: u128,
+        }
+
+        let test = Test {
+            min: i64::MIN,
+            umax: u64::MAX,
+            seq: vec![i64::MIN, i64::MAX],
+            int128: vec![i128::MIN, -1],
+            uint128: u128::MAX,
+        };
+        let deserialized: Test = from_str(&to_string(&test).unwrap()).unwrap();
+        assert_eq!(deserialized, test);
+    }
+
+    #[test]
+    fn test_missing_seq() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            seq: Vec<i32>,
+            option: Option<i32>,
+        }
+
+        let test: Test = from_str("INT32=1\n").unwrap();
+        let expected = Test {
+            int32: 1,
+            seq: Vec::new(),
+            option: None,
+        };
+        assert_eq!(test, expected);
+
+        // Other missing fields still error
+        assert_eq!(
+            from_str::<Test>("SEQ='1'\n").unwrap_err(),
+            Error::Message(String::from("missing field `int32`"))
+        );
+
+        let options = DeserializerOptions {
+            missing_seqs_as_empty: false,
+            ..DeserializerOptions::default()
+        };
+        assert_eq!(
+            from_str_with_options::<Test>("INT32=1\n", options).unwrap_err(),
+            Error::Message(String::from("missing field `seq`"))
+        );
+    }
+
+    #[test]
+    fn test_missing_seq_serde_default() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            name: String,
+            #[serde(default)]
+            port: u16,
+            #[serde(default)]
+            nested: Nested,
+            seq: Vec<i32>,
+            other: Other,
+        }
+
+        #[derive(Deserialize, Debug, Default, PartialEq)]
+        struct Nested {
+            host: String,
+            tags: Vec<String>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Other {
+            int32: i32,
+            seq: Vec<i32>,
+        }
+
+        let test: Test = from_str("NAME=\"x\"\nOTHER_INT32=1\n").unwrap();
+        let expected = Test {
+            name: String::from("x"),
+            port: 0,
+            nested: Nested::default(),
+            seq: Vec::new(),
+            other: Other {
+                int32: 1,
+                seq: Vec::new(),
+            },
+        };
+        assert_eq!(test, expected);
+    }
+
+    #[test]
+    fn test_nested_struct() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            nested: Nested,
+            other_int32: i32,
+        }
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Nested {
+            nested_again: NestedAgain,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct NestedAgain {
+            int32: i32,
+        }
+
+        let test = Test {
+            int32: 1,
+            nested: Nested {
+                nested_again: NestedAgain { int32: 1 },
+            },
+            other_int32: 1,
+        };
+        let input = to_string(&test).unwrap();
+        assert_eq!(
+            input,
+            "INT32=1\nNESTED_NESTED_AGAIN_INT32=1\nOTHER_INT32=1\n"
+        );
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+    }
+
+    #[test]
+    fn test_option() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            option_int32: Option<i32>,
+        }
+
+        let input = "INT32=1\nOPTION_INT32=1\n";
+        let expected = Test {
+            int32: 1,
+            option_int32: Some(1),
+        };
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+
+        let input = "# a comment\n\nINT32=1\n";
+        let expected = Test {
+            int32: 1,
+            option_int32: None,
+        };
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_ambiguous_key() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Nested {
+            again: i32,
+        }
+
+        // `nested.again`
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Split {
+            nested: Nested,
+        }
+
+        // `nested_again`
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Joined {
+            nested_again: i32,
+        }
+
+        // Both `nested.again` and `nested_again`
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Both {
+            nested: Nested,
+            nested_again: i32,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Other {
+            other: i32,
+        }
+
+        // `nested.other` and `nested_again` can be told apart
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Disjoint {
+            nested: Other,
+            nested_again: i32,
+        }
+
+        let input = "NESTED_AGAIN=1\n";
+        let expected = Split {
+            nested: Nested { again: 1 },
+        };
+        assert_eq!(from_str::<Split>(input).unwrap(), expected);
+
+        let expected = Joined { nested_again: 1 };
+        assert_eq!(from_str::<Joined>(input).unwrap(), expected);
+
+        assert_eq!(
+            from_str::<Both>(input).unwrap_err(),
+            Error::AmbiguousKey(String::from("NESTED_AGAIN"))
+        );
+
+        let input = "NESTED_OTHER=1\nNESTED_AGAIN=2\n";
+        let expected = Disjoint {
+            nested: Other { other: 1 },
+            nested_again: 2,
+        };
+        assert_eq!(from_str::<Disjoint>(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_quoted_scalars() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            boolean: bool,
+            int32: i32,
+            float64: f64,
+            string: String,
+        }
+
+        let expected = Test {
+            boolean: true,
+            int32: -1,
+            float64: 1.5,
+            string: String::from("text"),
+        };
+
+        let input = "BOOLEAN=true\nINT32=-1\nFLOAT64=1.5\nSTRING=text\n";
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+
+        let input =
+            "BOOLEAN=\"true\"\nINT32=\"-1\"\nFLOAT64=\"1.5\"\nSTRING=\"text\"\n";
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+
+        let input =
+            "BOOLEAN='true'\nINT32='-1'\nFLOAT64='1.5'\nSTRING='text'\n";
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+
+        // Only a single pair of quotes is stripped
+        let input =
+            "BOOLEAN=true\nINT32=-1\nFLOAT64=1.5\nSTRING=\"\"text\"\"\n";
+        let expected = Test {
+            string: String::from("\"text\""),
+            ..expected
+        };
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+
+        // Mismatched quotes are not stripped
+        let input = "BOOLEAN=\"true'\nINT32=-1\nFLOAT64=1.5\nSTRING=text\n";
+        assert_eq!(
+            from_str::<Test>(input).unwrap_err(),
+            Error::ParseBool(String::from("\"true'")).at_line(1)
+        );
+    }
+
+    #[test]
+    fn test_map_round_trip() {
+        use std::collections::{BTreeMap, HashMap};
+
+        let ints: HashMap<String, i32> =
+            vec![(String::from("A"), 1), (String::from("B"), 2)]
+                .into_iter()
+                .collect();
+        let deserialized: HashMap<String, i32> =
+            from_str(&to_string(&ints).unwrap()).unwrap();
+        assert_eq!(deserialized, ints);
+
+        let strings: BTreeMap<String, String> = vec![
+            (String::from("HOST"), String::from("x")),
+            (String::from("PORT"), String::from("80")),
+        ]
+        .into_iter()
+        .collect();
+        let deserialized: BTreeMap<String, String> =
+            from_str(&to_string(&strings).unwrap()).unwrap();
+        assert_eq!(deserialized, strings);
+
+        // Keys are uppercased when serializing, which can't be undone
+        let lowercase: BTreeMap<String, i32> =
+            vec![(String::from("host"), 1)].into_iter().collect();
+        let deserialized: BTreeMap<String, i32> =
+            from_str(&to_string(&lowercase).unwrap()).unwrap();
+        let expected: BTreeMap<String, i32> =
+            vec![(String::from("HOST"), 1)].into_iter().collect();
+        assert_eq!(deserialized, expected);
+    }
+
+    #[test]
+    fn test_ansi_c_quoted() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            string: String,
+        }
+
+        let test = Test {
+            string: String::from("multi\nline\tit's \u{1f600} \\ café"),
+        };
+        let options = SerializerOptions {
+            ascii_escape_values: true,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert_eq!(from_str::<Test>(&output).unwrap(), test);
+
+        let test: Test = from_str("STRING=$'\\x41\\n\\u00e9'").unwrap();
+        assert_eq!(test.string, "A\né");
+
+        assert_eq!(
+            from_str::<Test>("STRING=$'\\q'").unwrap_err(),
+            Error::InvalidEscape(String::from("\\q")).at_line(1)
+        );
+    }
+
+    #[test]
+    fn test_line_number() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Test {
+            int32: i32,
+            string: String,
+            seq: Vec<i32>,
+        }
+
+        let input = "INT32=1\n# comment\nSEQ='1,x'\nSTRING=\"s\"\n";
+        let err = from_str::<Test>(input).unwrap_err();
+        let expected = Error::ParseInt(String::from("x")).at_line(3);
+        assert_eq!(err, expected);
+        assert_eq!(err.to_string(), "line 3: invalid integer value x");
+
+        // Line continuations count as the line they start on
+        let input = "STRING=\"a\\\nb\"\nINT32=\\\n1\nSEQ\n";
+        let err = from_str::<Test>(input).unwrap_err();
+        assert_eq!(err, Error::Syntax.at_line(5));
+
+        // Every error can be displayed
+        let err = from_str::<Test>("garbage").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: syntax error");
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Maps {
+            seq: Vec<std::collections::BTreeMap<String, i32>>,
+        }
+
+        let err = from_str::<Maps>("SEQ='1'\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: expected a map");
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            nested: Nested,
+            seq: Vec<String>,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Nested {
+            string: String,
+            option_int32: Option<i32>,
+        }
+
+        let pairs = vec![
+            (String::from("INT32"), String::from("1")),
+            (String::from("NESTED_STRING"), String::from("s")),
+            (String::from("SEQ"), String::from("\"a\",\"b\"")),
+        ];
+        let expected = Test {
+            int32: 1,
+            nested: Nested {
+                string: String::from("s"),
+                option_int32: None,
+            },
+            seq: vec![String::from("a"), String::from("b")],
+        };
+        assert_eq!(from_pairs::<_, Test>(pairs).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            string: String,
+            escaped: String,
+        }
+
+        let input = "STRING=\"first \\\nsecond\"\nESCAPED=a\\\\\n";
+        let expected = Test {
+            string: String::from("first second"),
+            escaped: String::from("a\\\\"),
+        };
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_comment_char() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let input = "; generated\nINT32=1\n  ; indented\nSTRING=\"#s\"\n";
+        let options = DeserializerOptions {
+            comment_char: ';',
+            ..DeserializerOptions::default()
+        };
+        let test: Test = from_str_with_options(input, options).unwrap();
+        assert_eq!(
+            test,
+            Test {
+                int32: 1,
+                string: String::from("#s"),
+            }
+        );
+
+        // `#` is not a comment anymore
+        let options = DeserializerOptions {
+            comment_char: ';',
+            ..DeserializerOptions::default()
+        };
+        assert_eq!(
+            from_str_with_options::<Test>("# comment\nINT32=1\n", options)
+                .unwrap_err(),
+            Error::Syntax.at_line(1)
+        );
+    }
+
+    #[test]
+    fn test_single_line() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            string: String,
+            seq: Vec<String>,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("with spaces"),
+            seq: vec![String::from("a b"), String::from("c")],
+        };
+        let options = SerializerOptions {
+            single_line: true,
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        assert_eq!(input, "INT32=1 STRING=\"with spaces\" SEQ='\"a b\",\"c\"'");
+
+        let options = DeserializerOptions {
+            single_line: true,
+            ..DeserializerOptions::default()
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(&input, options).unwrap(),
+            test
+        );
+
+        // An unterminated quote is a syntax error of the first line
+        let options = DeserializerOptions {
+            single_line: true,
+            ..DeserializerOptions::default()
+        };
+        let err =
+            from_str_with_options::<Test>("INT32=\"x", options).unwrap_err();
+        assert_eq!(err, Error::Syntax.at_line(1));
+        assert_eq!(err.to_string(), "line 1: syntax error");
+    }
+
+    #[test]
+    fn test_assignment() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            seq: Vec<u32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            seq: vec![1, 2],
+        };
+        for assignment in ["=", " = "] {
+            let options = SerializerOptions {
+                assignment: Some(String::from(assignment)),
+                ..SerializerOptions::default()
+            };
+            let input = to_string_with_options(&test, options).unwrap();
+            let options = DeserializerOptions {
+                assignment: String::from(assignment),
+                ..DeserializerOptions::default()
+            };
+            assert_eq!(
+                from_str_with_options::<Test>(&input, options).unwrap(),
+                test
+            );
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = "INT32=1\nSTRING=\"a\"\nSEQ='1,2'\n";
+        let b = "INT32=2\nSTRING=\"a\"\nSEQ='1,2'\nNEW=true\n";
+        let expected = vec![
+            KeyDiff::Changed {
+                key: String::from("INT32"),
+                old: String::from("1"),
+                new: String::from("2"),
+            },
+            KeyDiff::Added {
+                key: String::from("NEW"),
+                value: String::from("true"),
+            },
+        ];
+        assert_eq!(diff(a, b).unwrap(), expected);
+
+        let expected = vec![
+            KeyDiff::Changed {
+                key: String::from("INT32"),
+                old: String::from("2"),
+                new: String::from("1"),
+            },
+            KeyDiff::Removed {
+                key: String::from("NEW"),
+                value: String::from("true"),
+            },
+        ];
+        assert_eq!(diff(b, a).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_from_str_many() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let input = "INT32=1\nSTRING=\"a\"\n\n---\nINT32=2\nSTRING=\"b\"\n";
+        let expected = vec![
+            Test {
+                int32: 1,
+                string: String::from("a"),
+            },
+            Test {
+                int32: 2,
+                string: String::from("b"),
+            },
+        ];
+        assert_eq!(from_str_many::<Test>(input, "\n---\n").unwrap(), expected);
+
+        let input = "INT32=1\nSTRING=\"a\"\n\n---\nINT32=x\nSTRING=\"b\"\n";
+        assert_eq!(
+            from_str_many::<Test>(input, "\n---\n"),
+            Err(Error::ParseInt(String::from("x")).at_line(5))
+        );
+    }
+
+    #[test]
+    fn test_bom() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("s"),
+        };
+        let options = SerializerOptions {
+            emit_bom: true,
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        assert_eq!(input, "\u{feff}INT32=1\nSTRING=\"s\"\n");
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+        assert_eq!(
+            from_reader_streaming::<_, Test>(input.as_bytes()).unwrap(),
+            test
+        );
+    }
+
+    #[test]
+    fn test_from_reader_streaming() {
+        use std::collections::BTreeMap;
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            map: BTreeMap<String, u32>,
+        }
+
+        let mut input = String::from("INT32=\\\n1\n");
+        for i in 0..10_000 {
+            input += &format!("# entry {}\nMAP_KEY{}={}\n", i, i, i);
+        }
+        let test: Test = from_reader_streaming(input.as_bytes()).unwrap();
+        assert_eq!(test.int32, 1);
+        assert_eq!(test.map.len(), 10_000);
+        assert_eq!(test.map["KEY9999"], 9999);
+        assert_eq!(test, from_str::<Test>(&input).unwrap());
+    }
+
+    #[test]
+    fn test_quote_all_round_trip() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            boolean: bool,
+            int32: i32,
+            uint64: u64,
+            float64: f64,
+            string: String,
+            seq: Vec<u32>,
+            option: Option<i8>,
+        }
+
+        let test = Test {
+            boolean: true,
+            int32: -1,
+            uint64: 2,
+            float64: 1.5,
+            string: String::from("text"),
+            seq: vec![3, 4],
+            option: Some(5),
+        };
+        let options = SerializerOptions {
+            quote_all: true,
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        let expected = "BOOLEAN=\"true\"\nINT32=\"-1\"\nUINT64=\"2\"\nFLOAT64=\"1.5\"\nSTRING=\"text\"\nSEQ='\"3\",\"4\"'\nOPTION=\"5\"\n";
+        assert_eq!(input, expected);
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+    }
+
+    #[test]
+    fn test_track_path() {
+        use std::collections::BTreeMap;
+
+        #[derive(Deserialize, Debug)]
+        struct Test {
+            #[allow(dead_code)]
+            nested: Nested,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Nested {
+            #[allow(dead_code)]
+            ports: BTreeMap<String, Vec<u16>>,
+        }
+
+        let input = "NESTED_PORTS_WEB='80,443,x'\n";
+        let options = DeserializerOptions {
+            track_path: true,
+            ..DeserializerOptions::default()
+        };
+        let err = from_str_with_options::<Test>(input, options).unwrap_err();
+        assert_eq!(err.path(), "nested.ports.WEB.2");
+        let inner = Error::ParseInt(String::from("x"));
+        let path = String::from("nested.ports.WEB.2");
+        assert_eq!(err, Error::Path(path, Box::new(inner)).at_line(1));
+        assert_eq!(from_str::<Test>(input).unwrap_err().path(), "");
+    }
+
+    #[test]
+    fn test_presence_flag_suffix() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            some: Option<String>,
+            none: Option<String>,
+        }
+
+        let test = Test {
+            some: Some(String::new()),
+            none: None,
+        };
+        let options = SerializerOptions {
+            presence_flag_suffix: Some(String::from("set")),
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        let options = || DeserializerOptions {
+            presence_flag_suffix: Some(String::from("set")),
+            ..DeserializerOptions::default()
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(&input, options()).unwrap(),
+            test
+        );
+
+        // The flag wins over the value.
+        let input = "SOME=text\nSOME_SET=false\n";
+        let expected = Test {
+            some: None,
+            none: None,
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(input, options()).unwrap(),
+            expected
+        );
+        let some = Some(String::from("text"));
+        assert_eq!(from_str::<Test>(input).unwrap().some, some);
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            name: String,
+            database: Database,
+            port: u16,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Database {
+            host: String,
+            pool: Pool,
+            tags: Vec<String>,
+            labels: BTreeMap<String, String>,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Pool {
+            size: u32,
+        }
+
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("ENV"), String::from("it's prod"));
+        let test = Test {
+            name: String::from("app"),
+            database: Database {
+                host: String::from("localhost"),
+                pool: Pool { size: 4 },
+                tags: vec![String::from("a"), String::from("b c")],
+                labels,
+            },
+            port: 80,
+        };
+        let options = SerializerOptions {
+            nested_blocks: true,
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        assert_eq!(from_str::<Test>(&input).unwrap(), test);
+
+        // Errors give the line of the block.
+        let input = "NAME=app\nDATABASE=$'HOST=h\\nPOOL=$\\'SIZE=x\\''\n";
+        assert_eq!(
+            from_str::<Test>(input).unwrap_err(),
+            Error::ParseInt(String::from("x")).at_line(2)
+        );
+    }
+
+    #[test]
+    fn test_require_all_fields() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            int32: i32,
+            option: Option<u32>,
+            nested: Nested,
+        }
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Nested {
+            string: String,
+            seq: Vec<u32>,
+        }
+
+        let options = || DeserializerOptions {
+            require_all_fields: true,
+            ..DeserializerOptions::default()
+        };
+        let input = "INT32=1\nNESTED_STRING=a\nNESTED_SEQ=2\n";
+        let expected = Test {
+            int32: 1,
+            option: None,
+            nested: Nested {
+                string: String::from("a"),
+                seq: vec![2],
+            },
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(input, options()).unwrap(),
+            expected
+        );
+
+        let input = "INT32=1\nNESTED_SEQ=2\n";
+        assert_eq!(
+            from_str_with_options::<Test>(input, options()),
+            Err(Error::MissingKey(String::from("NESTED_STRING")))
+        );
+
+        let input = "INT32=1\nNESTED_STRING=a\n";
+        assert_eq!(
+            from_str_with_options::<Test>(input, options()),
+            Err(Error::MissingKey(String::from("NESTED_SEQ")))
+        );
+    }
+
+    #[test]
+    fn test_properties() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            server: Server,
+            ratio: f64,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Server {
+            max_threads: u32,
+            motd: String,
+            tags: Vec<String>,
+            option: Option<bool>,
+        }
+
+        let test = Test {
+            server: Server {
+                max_threads: 8,
+                motd: String::from(" C:\\app\nkey=value"),
+                tags: vec![String::from("a"), String::from("b")],
+                option: Some(true),
+            },
+            ratio: 0.5,
+        };
+        let options = SerializerOptions {
+            format: Format::DottedProperties,
+            ..SerializerOptions::default()
+        };
+        let input = to_string_with_options(&test, options).unwrap();
+        let options = || DeserializerOptions {
+            properties: true,
+            ..DeserializerOptions::default()
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(&input, options()).unwrap(),
+            test
+        );
+
+        // Other separators, and comments.
+        let input = "! comment\n\
+                     server.max_threads : 8\n\
+                     server.motd welcome\n\
+                     server.tags=a,b\n\
+                     ratio=0.5\n";
+        let expected = Test {
+            server: Server {
+                max_threads: 8,
+                motd: String::from("welcome"),
+                tags: vec![String::from("a"), String::from("b")],
+                option: None,
+            },
+            ratio: 0.5,
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(input, options()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_repeat_keys_as_seq() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Test {
+            tag: Vec<String>,
+            seq: Vec<u32>,
+            int32: i32,
+        }
+
+        let input = "TAG=a\nTAG=\"b c\"\nINT32=1\nSEQ='1,2'\nTAG=d,e\n";
+        let options = DeserializerOptions {
+            repeat_keys_as_seq: true,
+            ..DeserializerOptions::default()
+        };
+        let expected = Test {
+            tag: vec![
+                String::from("a"),
+                String::from("b c"),
+                String::from("d,e"),
+            ],
+            seq: vec![1, 2],
+            int32: 1,
+        };
+        assert_eq!(
+            from_str_with_options::<Test>(input, options).unwrap(),
+            expected
+        );
+
+        // The last one wins otherwise.
+        let expected = Test {
+            tag: vec![String::from("d"), String::from("e")],
+            seq: vec![1, 2],
+            int32: 1,
+        };
+        assert_eq!(from_str::<Test>(input).unwrap(), expected);
+    }
+}
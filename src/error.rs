@@ -42,6 +42,102 @@ pub enum Error {
     ExpectedMapEnd,
     ExpectedEnum,
     TrailingCharacters,
+
+    // Writing the serialized output to an `io::Write` failed. The underlying
+    // `io::Error` is neither `Clone` nor `PartialEq`, so only its message is
+    // kept.
+    Io(String),
+    // A sequence or a map was found under this key while the serializer only
+    // accepts scalars.
+    UnsupportedNesting(String),
+    // The float under this key doesn't survive being written with the
+    // configured precision.
+    PrecisionLoss(String),
+    // The float under this key is NaN or infinite, see `NonFinitePolicy`.
+    NonFiniteFloat(String),
+    // The string under this key spans several lines, which the output format
+    // can't represent.
+    MultilineValue(String),
+    // Map keys must be strings, chars, integers or unit variants to be used in
+    // a variable name.
+    InvalidMapKey,
+    // The bytes under this key aren't valid UTF-8, see `BytesEncoding::Utf8`.
+    InvalidUtf8(String),
+    // Two variables have this same name.
+    DuplicateKey(String),
+    // Different names, e.g. `fooBar` and `foobar`, were cased to this same
+    // key.
+    CaseCollision(String),
+    // A `Tracked` pointer under this key points back to a value being
+    // serialized, see `detect_cycles`.
+    CycleDetected(String),
+    // The variable isn't among the allowed ones, see `to_string_checked`.
+    KeyNotAllowed(String),
+    // This variable name isn't a valid shell variable name.
+    InvalidKey(String),
+    // `verify` found no `# sha256=...` line at the end of the content.
+    MissingChecksum,
+    // The checksum doesn't match the content, which was edited since.
+    ChecksumMismatch,
+
+    // The deserializer found a key, but only as the prefix of other keys while
+    // the target type expected a value for it.
+    MissingValue(String),
+    // No variable was found for this key, see `require_all_fields`.
+    MissingKey(String),
+    // The raw value on the right hand side of the `=` could not be parsed into
+    // the expected type.
+    ParseBool(String),
+    ParseInt(String),
+    ParseFloat(String),
+    MalformedSequence(String),
+    // A `$'...'` value holds an escape sequence that isn't supported.
+    InvalidEscape(String),
+    // The key can be split into the target type's fields in more than one way,
+    // e.g. `NESTED_AGAIN` for both `nested.again` and `nested_again`.
+    AmbiguousKey(String),
+    // The error happened on this 1-based line of the input.
+    Line(usize, Box<Error>),
+    // The error happened at this serde path, see `track_path`.
+    Path(String, Box<Error>),
+}
+
+impl Error {
+    // Attaches the 1-based line of the input the error happened on, unless
+    // it is unknown (0) or already attached.
+    pub(crate) fn at_line(self, line: usize) -> Error {
+        match self {
+            Error::Line(..) => self,
+            _ if line == 0 => self,
+            err => Error::Line(line, Box::new(err)),
+        }
+    }
+
+    // Prepends a field name, map key or sequence index to the path of the
+    // error, as it bubbles up from where it happened. The line, if any, stays
+    // outermost.
+    pub(crate) fn in_path(self, segment: &str) -> Error {
+        match self {
+            Error::Line(line, err) => {
+                Error::Line(line, Box::new(err.in_path(segment)))
+            }
+            Error::Path(path, err) => {
+                Error::Path(format!("{}.{}", segment, path), err)
+            }
+            err => Error::Path(segment.to_string(), Box::new(err)),
+        }
+    }
+
+    // The serde path the error happened at, e.g. `items.1.name`: field names,
+    // map keys and sequence indices joined with `.`. Only recorded with
+    // `track_path`, empty otherwise or when the error isn't below any field.
+    pub fn path(&self) -> String {
+        match self {
+            Error::Line(_, err) => err.path(),
+            Error::Path(path, _) => path.clone(),
+            _ => String::new(),
+        }
+    }
 }
 
 impl ser::Error for Error {
@@ -61,10 +157,80 @@ impl Display for Error {
         match self {
             Error::Message(msg) => write!(f, "{}", msg),
             Error::Eof => f.write_str("unexpected end of input"),
-            /* and so forth */
-            _ => unimplemented!(),
+            Error::Syntax => f.write_str("syntax error"),
+            Error::ExpectedBoolean => f.write_str("expected a boolean"),
+            Error::ExpectedInteger => f.write_str("expected an integer"),
+            Error::ExpectedString => f.write_str("expected a string"),
+            Error::ExpectedNull => f.write_str("expected null"),
+            Error::ExpectedArray => f.write_str("expected a sequence"),
+            Error::ExpectedArrayComma => {
+                f.write_str("expected a comma between elements")
+            }
+            Error::ExpectedArrayEnd => {
+                f.write_str("expected the end of the sequence")
+            }
+            Error::ExpectedMap => f.write_str("expected a map"),
+            Error::ExpectedMapColon => {
+                f.write_str("expected a colon after the map key")
+            }
+            Error::ExpectedMapComma => {
+                f.write_str("expected a comma between entries")
+            }
+            Error::ExpectedMapEnd => f.write_str("expected the end of the map"),
+            Error::ExpectedEnum => f.write_str("expected an enum"),
+            Error::TrailingCharacters => f.write_str("trailing characters"),
+            Error::Io(msg) => write!(f, "io error: {}", msg),
+            Error::UnsupportedNesting(key) => {
+                write!(f, "unsupported sequence or map for {}", key)
+            }
+            Error::PrecisionLoss(key) => {
+                write!(f, "precision loss for float {}", key)
+            }
+            Error::NonFiniteFloat(key) => {
+                write!(f, "non-finite float for {}", key)
+            }
+            Error::MultilineValue(key) => {
+                write!(f, "multiline value for {}", key)
+            }
+            Error::InvalidMapKey => f.write_str("invalid map key"),
+            Error::InvalidUtf8(key) => write!(f, "invalid UTF-8 for {}", key),
+            Error::DuplicateKey(key) => write!(f, "duplicate key {}", key),
+            Error::CaseCollision(key) => {
+                write!(f, "different names cased to the same key {}", key)
+            }
+            Error::CycleDetected(key) => write!(f, "cycle detected at {}", key),
+            Error::KeyNotAllowed(key) => write!(f, "key not allowed {}", key),
+            Error::InvalidKey(key) => write!(f, "invalid key {}", key),
+            Error::MissingChecksum => f.write_str("missing checksum"),
+            Error::ChecksumMismatch => f.write_str("checksum mismatch"),
+            Error::MissingValue(key) => write!(f, "missing value for {}", key),
+            Error::MissingKey(key) => write!(f, "missing key {}", key),
+            Error::ParseBool(value) => {
+                write!(f, "invalid boolean value {}", value)
+            }
+            Error::ParseInt(value) => {
+                write!(f, "invalid integer value {}", value)
+            }
+            Error::ParseFloat(value) => {
+                write!(f, "invalid float value {}", value)
+            }
+            Error::MalformedSequence(value) => {
+                write!(f, "malformed sequence {}", value)
+            }
+            Error::InvalidEscape(value) => {
+                write!(f, "invalid escape sequence in {}", value)
+            }
+            Error::AmbiguousKey(key) => write!(f, "ambiguous key {}", key),
+            Error::Line(line, err) => write!(f, "line {}: {}", line, err),
+            Error::Path(path, err) => write!(f, "{}: {}", path, err),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err.to_string())
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2018 Serde Developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Functions for `#[serde(serialize_with = "...")]`, for values whose
+// serialized form differs from what their type would give.
+
+use crate::ser::RawValue;
+use serde::ser::Error;
+use serde::{Serialize, Serializer};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Writes an amount of cents with exactly two decimals, `1999` as
+// `PRICE=19.99`, computed on the integer so that no float rounding creeps in.
+// The amount is written unquoted, as a `RawValue`.
+pub fn serialize_cents<S>(cents: &i64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let sign = if *cents < 0 { "-" } else { "" };
+    let abs = cents.unsigned_abs();
+    let amount = format!("{}{}.{:02}", sign, abs / 100, abs % 100);
+    RawValue(amount).serialize(serializer)
+}
+
+// Points in time `serialize_unix_secs` accepts.
+pub trait UnixTime {
+    // The whole seconds since the Unix epoch, `None` before it.
+    fn unix_secs(&self) -> Option<u64>;
+}
+
+impl UnixTime for SystemTime {
+    fn unix_secs(&self) -> Option<u64> {
+        self.duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs())
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> UnixTime for chrono::DateTime<Tz> {
+    fn unix_secs(&self) -> Option<u64> {
+        let secs = self.timestamp();
+        if secs < 0 {
+            return None;
+        }
+        Some(secs as u64)
+    }
+}
+
+// Writes a point in time as the whole seconds since the Unix epoch,
+// `TS=1700000000`. Times before the epoch error.
+pub fn serialize_unix_secs<T, S>(
+    time: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    T: UnixTime,
+    S: Serializer,
+{
+    match time.unix_secs() {
+        Some(secs) => serializer.serialize_u64(secs),
+        None => Err(S::Error::custom("time before the Unix epoch")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serialize_cents, serialize_unix_secs};
+    use crate::error::Error;
+    use crate::ser::to_string;
+    use serde_derive::Serialize;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_serialize_cents() {
+        #[derive(Serialize)]
+        struct Test {
+            #[serde(serialize_with = "serialize_cents")]
+            price: i64,
+            #[serde(serialize_with = "serialize_cents")]
+            refund: i64,
+            #[serde(serialize_with = "serialize_cents")]
+            min: i64,
+        }
+
+        let test = Test {
+            price: 1999,
+            refund: -5,
+            min: i64::MIN,
+        };
+        let expected = "PRICE=19.99\nREFUND=-0.05\nMIN=-92233720368547758.08\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_serialize_unix_secs() {
+        #[derive(Serialize)]
+        struct Test {
+            #[serde(serialize_with = "serialize_unix_secs")]
+            ts: SystemTime,
+        }
+
+        let test = Test {
+            ts: UNIX_EPOCH + Duration::from_millis(1_700_000_000_999),
+        };
+        assert_eq!(to_string(&test).unwrap(), "TS=1700000000\n");
+
+        let test = Test {
+            ts: UNIX_EPOCH - Duration::from_secs(1),
+        };
+        assert_eq!(
+            to_string(&test).unwrap_err(),
+            Error::Message(String::from("time before the Unix epoch"))
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_serialize_unix_secs_chrono() {
+        use chrono::{DateTime, TimeZone, Utc};
+
+        #[derive(Serialize)]
+        struct Test {
+            #[serde(serialize_with = "serialize_unix_secs")]
+            ts: DateTime<Utc>,
+        }
+
+        let test = Test {
+            ts: Utc.with_ymd_and_hms(2023, 11, 14, 22, 13, 20).unwrap(),
+        };
+        assert_eq!(to_string(&test).unwrap(), "TS=1700000000\n");
+    }
+}
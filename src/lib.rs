@@ -6,8 +6,28 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+mod de;
 mod error;
+pub mod helpers;
 mod ser;
 
+pub use crate::de::{
+    diff, from_pairs, from_reader_streaming, from_str, from_str_many,
+    from_str_with_options, DeserializerOptions, KeyDiff,
+};
 pub use crate::error::{Error, Result};
-pub use crate::ser::{to_string, Serializer};
+#[cfg(feature = "json")]
+pub use crate::ser::to_nested_map;
+#[cfg(feature = "flate2")]
+pub use crate::ser::to_writer_gzip;
+#[cfg(feature = "sha2")]
+pub use crate::ser::verify;
+pub use crate::ser::{
+    expected_keys, keys_of, to_map, to_string, to_string_and_map,
+    to_string_checked, to_string_filtered, to_string_many,
+    to_string_non_default, to_string_prefixed, to_string_with_options,
+    to_string_with_trace, to_vec, to_writer, to_writer_with_options,
+    BytesEncoding, CaseStyle, EmptySeqPolicy, EnvPrefix, EnvRef, FlagStyle,
+    FloatFormat, Format, KeyFilter, MapFormat, NonFinitePolicy, Quoting,
+    RawValue, Serializer, SerializerOptions, Tracked,
+};
@@ -8,12 +8,385 @@
 
 use crate::error::{Error, Result};
 use serde::ser::{self, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::ops::Deref;
 
-pub struct Serializer {
+pub struct Serializer<'t> {
     // This string starts empty and bash env vars are appended as values are serialized.
     output: String,
     keys: Vec<String>,
+    // `keys` already joined with `_`, kept up to date as keys are pushed and
+    // popped so that each variable doesn't have to join the whole stack again.
+    // `key_lens` holds the length of `key` before each push.
+    key: String,
+    key_lens: Vec<usize>,
+    // How many of `keys` come before the value being serialized, i.e. the
+    // `prefix`. Structs deeper than this are nested ones.
+    root_depth: usize,
+    // The name each of `keys` was made from, when `case_style` changed it.
+    names: Vec<Option<String>>,
+    // When `case_style` changes names, the names `keys` were made from,
+    // joined like `key` and kept up to date the same way with
+    // `original_lens`.
+    original: String,
+    original_lens: Vec<usize>,
+    // The original name of each variable written so far, to detect
+    // `Error::CaseCollision`.
+    originals: BTreeMap<String, String>,
     is_seq: bool,
+    // The elements of the sequence being serialized are kept apart until the
+    // end of the sequence, so that they can be sorted before being written.
+    elements: Vec<String>,
+    // The chars among `elements`, for `chars_as_string`.
+    chars: String,
+    // The key of the sequence being serialized, `None` if it is dropped.
+    seq_key: Option<String>,
+    // With `treat_pair_seq_as_map`, whether the sequence being serialized
+    // holds 2-tuples, `None` until its first element is seen.
+    pairs: Option<bool>,
+    // The key of each variable written and where it starts in `output`.
+    vars: Vec<(String, usize)>,
+    // Write values as they are once sourced, for `to_map`. The value of each
+    // variable is then kept in `values` too.
+    raw: bool,
+    values: Vec<String>,
+    // Keep the value of each variable as it is once sourced in `values`,
+    // while writing it as usual, for `to_string_and_map`. `sourced` holds the
+    // one being written, `sourced_elements` those of the sequence being
+    // serialized, and `sourced_element` those of the element being
+    // serialized.
+    sourced_values: bool,
+    sourced: Option<String>,
+    sourced_elements: Vec<String>,
+    sourced_element: String,
+    // With `MapFormat::IndexedEntries`, the index of the next indexed entry
+    // of each map being serialized, innermost last, and whether the entry
+    // being serialized is indexed.
+    map_indices: Vec<(usize, bool)>,
+    // The index of the next field of each tuple variant being serialized,
+    // innermost last.
+    variant_indices: Vec<usize>,
+    // The structs and `Tracked` pointers being serialized, innermost last.
+    frames: Vec<Frame>,
+    // How many keys there are at each `Some` being serialized, innermost
+    // last, so that only the outermost of nested options writes its
+    // `presence_flag_suffix` flag.
+    options_open: Vec<usize>,
+    // With `debug_trace`, where a line is written as each scalar is
+    // serialized, see `to_string_with_trace`.
+    trace: Option<&'t mut dyn io::Write>,
+    // With `nested_blocks`, the serializers of the documents enclosing the
+    // one being serialized, innermost last.
+    blocks: Vec<Serializer<'t>>,
+    options: SerializerOptions,
+}
+
+// A struct being serialized.
+#[derive(Clone, PartialEq)]
+enum Frame {
+    // Whether it is folded by `fold_single_field_structs`.
+    Struct(bool),
+    // A `Tracked` pointer, with its address once its first field is seen.
+    // These don't count as structs for `section_headers` and
+    // `nested_blocks`.
+    Tracked(Option<String>),
+}
+
+// Receives the segments of a key, e.g. `["NESTED", "INT32"]`, and returns the
+// segments to use instead, or `None` to drop the variable.
+pub type KeyFilter = Box<dyn Fn(&[String]) -> Option<Vec<String>>>;
+
+// Options changing the env vars produced by the `Serializer`. The default
+// options write what `to_string` writes: uppercased keys joined with `_`, no
+// prefix, one double quoted `KEY="value"` per line ending with `\n`.
+#[derive(Default)]
+pub struct SerializerOptions {
+    // Sort the elements of every sequence by their serialized form. Sets like
+    // `HashSet` have no stable iteration order, this makes their output
+    // deterministic.
+    pub sort_seqs: bool,
+    // Only allow values that end up as a single scalar variable. Sequences and
+    // maps error with `Error::UnsupportedNesting` and have to be flattened
+    // into structs first.
+    pub scalars_only: bool,
+    // Leading segment removed from every key starting with it, along with the
+    // `_` after it: `Some("CONFIG")` turns `CONFIG_HOST` into `HOST`.
+    pub strip_prefix: Option<String>,
+    // Leading segment added to every key: `Some("APP")` turns `HOST` into
+    // `APP_HOST`.
+    pub prefix: Option<String>,
+    // How string values are quoted.
+    pub quoting: Quoting,
+    // Error with `Error::MultilineValue` on strings containing `\n` or `\r`,
+    // for consumers reading the output line by line.
+    pub forbid_multiline_values: bool,
+    // Write strings under keys ending with `_PATH` or `_DIR` in single quotes,
+    // `KEY='~/$HOME'`, so that the shell doesn't expand anything in them.
+    pub quote_paths: bool,
+    // Write strings holding a plain decimal number, like `1.50` or `-3`,
+    // unquoted as if they were numbers. Types like `rust_decimal::Decimal`
+    // serialize to such strings.
+    pub unquoted_numeric_strings: bool,
+    // Quote bools and numbers like strings, `KEY="1"`, for tools expecting
+    // every value to be quoted. This overrides `unquoted_numeric_strings`.
+    pub quote_all: bool,
+    // Write sequences of chars as a single string: `KEY="abc"` instead of
+    // `KEY='"a","b","c"'`.
+    pub chars_as_string: bool,
+    // Write string elements of sequences without their own quotes,
+    // `KEY='a,b'` instead of `KEY='"a","b"'`, so that splitting the sourced
+    // value on `,` with `IFS` gives back the elements. Elements containing a
+    // `,` can't be told apart anymore.
+    pub unquoted_seq_elements: bool,
+    // How field names, map keys and variant names are cased in the keys.
+    pub case_style: CaseStyle,
+    // Use the names as given by serde, overriding `case_style` with
+    // `CaseStyle::Preserve`, for types relying on `#[serde(rename_all)]` or
+    // `#[serde(rename)]` to name their variables.
+    pub respect_serde_rename_all: bool,
+    // Prepend `reserved_keyword_prefix` to the keys that are shell keywords,
+    // whatever their case: `DO` becomes `VAR_DO`.
+    pub prefix_reserved_keywords: bool,
+    // The prefix of `prefix_reserved_keywords`, `VAR_` when `None`.
+    pub reserved_keyword_prefix: Option<String>,
+    // Consulted before each variable is written, to rename or drop it.
+    pub key_filter: Option<KeyFilter>,
+    // Called with the depth of each segment of the keys but the first, 1 for
+    // the second one, to get what joins it to the segments before, `_` when
+    // `None`: `|depth| if depth == 1 { "__" } else { "_" }` gives `A__B_C`.
+    // `strip_prefix` then removes the joiner of depth 1.
+    pub key_joiner: Option<fn(usize) -> &'static str>,
+    // Maximum length of the lines holding a string value. Longer values are
+    // split with `\` line continuations, which the shell removes when
+    // sourcing. Single quotes don't support continuations, so this has no
+    // effect with `Quoting::Printf` nor on sequences.
+    pub wrap_width: Option<usize>,
+    // Number of decimals floats are written with. When set, this wins over
+    // `float_format`, like `FloatFormat::FixedDecimals`.
+    pub float_precision: Option<usize>,
+    // How floats are written.
+    pub float_format: FloatFormat,
+    // Error with `Error::PrecisionLoss` when a float written with a fixed
+    // number of decimals doesn't parse back to the same value.
+    pub error_on_precision_loss: bool,
+    // What to do with NaN and infinite floats.
+    pub nonfinite: NonFinitePolicy,
+    // How sequences without any element are written.
+    pub empty_seq: EmptySeqPolicy,
+    // Write `()` and unit structs as `KEY=""` instead of omitting them.
+    pub unit_as_empty_string: bool,
+    // Write unit variants as their index, unquoted like numbers, instead of
+    // their name: `STATUS=2` for the third variant.
+    pub enum_as_index: bool,
+    // Omit `false` bools like `None`, for flags that are only set when true.
+    pub skip_false_bools: bool,
+    // How bools are written.
+    pub flag_style: FlagStyle,
+    // How byte slices, e.g. from `serde_bytes`, are written.
+    pub bytes_encoding: BytesEncoding,
+    // Value written, quoted like strings, for `None` instead of omitting the
+    // variable: `Some("__UNSET__")` writes `KEY="__UNSET__"`.
+    pub none_placeholder: Option<String>,
+    // Write a companion flag for each `Option`, under its key followed by
+    // this segment: `Some("SET")` writes `KEY=value` and `KEY_SET=true` for
+    // `Some`, only `KEY_SET=false` for `None`. Options inside sequences get
+    // no flag.
+    pub presence_flag_suffix: Option<String>,
+    // What separates keys from values, `=` when `None`. Anything else, like
+    // `: ` or ` = `, is not shell syntax anymore, but is read back by the
+    // deserializer given the same `assignment`.
+    pub assignment: Option<String>,
+    // Written right after each value, before the newline, e.g. `;` for
+    // `KEY=value;`. The deserializer doesn't strip it.
+    pub line_suffix: Option<String>,
+    // The kind of file produced.
+    pub format: Format,
+    // Separate the variables with spaces instead of newlines, to prefix a
+    // command with them: `KEY1=v1 KEY2=v2 cmd`.
+    pub single_line: bool,
+    // Start the output with a UTF-8 byte order mark, for consumers expecting
+    // one. The deserializer ignores it.
+    pub emit_bom: bool,
+    // Append a `# sha256=...` line, the SHA-256 of everything before it, for
+    // `verify` to check that the output wasn't edited.
+    #[cfg(feature = "sha2")]
+    pub emit_checksum: bool,
+    // Check the variables once everything is serialized, before returning
+    // any output: keys must be valid shell variable names, and unique. Keys
+    // can collide through `key_filter`, or when nested names are joined into
+    // existing ones. Names only differing by case always error with
+    // `Error::CaseCollision` instead.
+    pub validate: bool,
+    // Record the key stack and value of each scalar, see
+    // `to_string_with_trace`.
+    pub debug_trace: bool,
+    // Record in errors the serde path they happened at, the field names, map
+    // keys and sequence indices as given to the serializer, see
+    // `Error::path`.
+    pub track_path: bool,
+    // Write a `# type` comment above each variable, e.g. `# int32`.
+    pub annotate_types: bool,
+    // Write a `# [field]` comment before the variables of each struct nested
+    // in the top-level one, `field` being its name.
+    pub section_headers: bool,
+    // Write string values holding non-ASCII characters with ANSI-C quoting,
+    // `KEY=$'caf\u00e9'`, so that the output is pure ASCII. Other values are
+    // quoted as usual. Bash only decodes `\u` escapes in a UTF-8 locale.
+    // This has no effect on sequences, nor with `Format::DockerEnvFile`.
+    pub ascii_escape_values: bool,
+    // Keys to write first, in this order. The variables not listed follow in
+    // their usual order. Keys are matched case insensitively against the
+    // written names, after `key_filter` and `strip_prefix`.
+    pub field_order: Option<Vec<String>>,
+    // How maps are written.
+    pub map_format: MapFormat,
+    // Leave out the field name of nested structs having a single field.
+    pub fold_single_field_structs: bool,
+    // Write sequences of 2-tuples like `Vec<(String, String)>` as maps, the
+    // first element of each tuple being the key: `KEY_FIRST=second`.
+    pub treat_pair_seq_as_map: bool,
+    // Error with `Error::CycleDetected` when a `Tracked` pointer points to a
+    // value that is already being serialized, instead of recursing until the
+    // stack overflows.
+    pub detect_cycles: bool,
+    // Write each nested struct as a document of its own, in a single ANSI-C
+    // quoted variable: `NESTED=$'KEY1=1\nKEY2="v2"'` instead of
+    // `NESTED_KEY1=1` and `NESTED_KEY2="v2"`. Sourcing the output, then
+    // `eval "$NESTED"`, gives back the inner variables. `validate` and the
+    // case collision checks apply to each document on its own.
+    pub nested_blocks: bool,
+    // The only variables that may be written: error with
+    // `Error::KeyNotAllowed` on the first variable whose name isn't in the
+    // set. Names are the ones written, e.g. `DB_HOST`. With `nested_blocks`,
+    // both the variable holding a block, e.g. `DB`, and the names written in
+    // its document, e.g. `HOST`, have to be in the set.
+    pub allowed_keys: Option<HashSet<String>>,
+}
+
+// Which tool the output is meant for, when its syntax differs from bash.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    // A file meant to be sourced by bash.
+    #[default]
+    Bash,
+    // A file for `docker run --env-file`. Docker takes everything after the
+    // `=` literally, so values are never quoted nor escaped (`quoting` is
+    // ignored), and values spanning several lines error with
+    // `Error::MultilineValue`.
+    DockerEnvFile,
+    // A Java properties file: `a.b.c=value`. Names are joined with `.`
+    // (unless `key_joiner` is set) and keep their case, whatever
+    // `case_style`. Values aren't quoted, and both keys and values are
+    // escaped the properties way: `\\`, `\n`, and in keys `\:`, `\=` and
+    // `\ `.
+    DottedProperties,
+}
+
+// How bools are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlagStyle {
+    // `KEY=true` and `KEY=false`.
+    #[default]
+    TrueFalse,
+    // `KEY=1` and `KEY=0`.
+    OneZero,
+    // `KEY=1` when true, nothing when false: the variable being set is the
+    // flag. Same as `OneZero` with `skip_false_bools`.
+    Presence,
+}
+
+// How finite floats are written. None of them depends on the platform: they
+// are all implemented by the standard library's formatting.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FloatFormat {
+    // The fewest digits parsing back to the same float, `0.1` or `100`.
+    #[default]
+    Shortest,
+    // This many decimals, `1.10` for 2.
+    FixedDecimals(usize),
+    // The fewest digits in scientific notation, `1e-1` or `1.5e2`.
+    Scientific,
+}
+
+// How NaN and infinite floats are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonFinitePolicy {
+    // Error with `Error::NonFiniteFloat`.
+    #[default]
+    Error,
+    // Quoted like strings: `KEY="NaN"`, `KEY="inf"` and `KEY="-inf"`, which
+    // the deserializer reads back.
+    AsString,
+    // Omitted like `None`. Inside a sequence, the element is left empty.
+    AsNull,
+}
+
+// How sequences without any element are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EmptySeqPolicy {
+    // Like the other sequences: `KEY=''`.
+    #[default]
+    EmptyQuotes,
+    // Omitted like `None`.
+    Omit,
+    // As an empty bash array, `KEY=()`. Only with `Format::Bash`, other
+    // formats write an empty value.
+    EmptyParens,
+}
+
+// How byte slices are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BytesEncoding {
+    // A sequence of numbers, `KEY='104,105'`.
+    #[default]
+    Seq,
+    // A string, `KEY="hi"`. Bytes that aren't valid UTF-8 error with
+    // `Error::InvalidUtf8`.
+    Utf8,
+}
+
+// How names are turned into segments of the variable names.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CaseStyle {
+    // `field` becomes `FIELD`.
+    #[default]
+    Upper,
+    // Names are used exactly as given, `dbHost` stays `dbHost`.
+    Preserve,
+}
+
+// How the entries of a map end up in the variable names.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MapFormat {
+    // `MAP_KEY=value`, each key is a segment of the variable names.
+    #[default]
+    KeyedNested,
+    // `MAP_0_KEY="key"` and `MAP_0_VALUE=value` for the entries whose keys
+    // aren't identifiers, numbered in iteration order. Those keys are written
+    // as values, so they don't have to fit in a variable name. Entries with
+    // identifier keys, e.g. `host`, are written as with `KeyedNested`.
+    IndexedEntries,
+}
+
+// How string values are written on the right hand side of the `=`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Quoting {
+    // `KEY="value"`, the value is written as is.
+    #[default]
+    Double,
+    // `KEY="value"`, with `"`, `\`, `$` and `` ` `` escaped so that sourcing
+    // the output gives back exactly the value.
+    Safe,
+    // `KEY=value`. Fine for values like numbers or dates (`2024-01-02`), but
+    // anything containing spaces or shell characters will not source
+    // correctly.
+    None,
+    // `KEY=$(printf '\001...')`, every byte outside of printable ASCII is
+    // written as an octal escape, so the value can hold control characters
+    // and still be sourced byte for byte. The shell strips trailing newlines
+    // from command substitutions, and can't hold NUL bytes in variables.
+    Printf,
 }
 
 // Serialize to env vars and output a String with `to_string`.
@@ -21,577 +394,5259 @@ pub fn to_string<T>(value: &T) -> Result<String>
 where
     T: Serialize,
 {
-    let mut serializer = Serializer {
-        output: String::new(),
-        keys: Vec::new(),
-        is_seq: false,
-    };
-    value.serialize(&mut serializer)?;
-    Ok(serializer.output)
+    to_string_with_options(value, SerializerOptions::default())
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
-    type Ok = ();
+// Same as `to_string`, with the given `SerializerOptions`.
+pub fn to_string_with_options<T>(
+    value: &T,
+    options: SerializerOptions,
+) -> Result<String>
+where
+    T: Serialize,
+{
+    let mut output = String::new();
+    serialize_into(value, Serializer::new(options), &mut output)?;
+    Ok(output)
+}
 
-    type Error = Error;
+// Serialize to a map of the variables and their values, as they are once
+// sourced: unquoted, unescaped, and sequences without their outer quotes.
+// `Quoting`, `Format` and options adding text around the variables have no
+// effect.
+pub fn to_map<T>(value: &T) -> Result<BTreeMap<String, String>>
+where
+    T: Serialize,
+{
+    let mut map = BTreeMap::new();
+    serialize_into(
+        value,
+        Serializer::new(SerializerOptions::default()),
+        &mut map,
+    )?;
+    Ok(map)
+}
 
-    type SerializeSeq = Self;
-    type SerializeTuple = Self;
-    type SerializeTupleStruct = Self;
-    type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
+// Same as `to_string` and `to_map` at once, for a single serialization: the
+// output, and the variables as they are once sourced.
+pub fn to_string_and_map<T>(
+    value: &T,
+) -> Result<(String, BTreeMap<String, String>)>
+where
+    T: Serialize,
+{
+    let mut both = (String::new(), BTreeMap::new());
+    serialize_into(
+        value,
+        Serializer::new(SerializerOptions::default()),
+        &mut both,
+    )?;
+    Ok(both)
+}
 
-    fn serialize_bool(self, v: bool) -> Result<()> {
-        self.output += if v { "true" } else { "false" };
-        Ok(())
-    }
+// Serialize to a tree of the variables, grouped the way the deserializer
+// groups them: each key is split on `_`, and each segment is one level of
+// nested objects. The values are strings, as with `to_map`. A key that is
+// also the prefix of other keys keeps its value under `""`.
+#[cfg(feature = "json")]
+pub fn to_nested_map<T>(value: &T) -> Result<serde_json::Value>
+where
+    T: Serialize,
+{
+    let mut tree = serde_json::Value::Object(serde_json::Map::new());
+    serialize_into(
+        value,
+        Serializer::new(SerializerOptions::default()),
+        &mut tree,
+    )?;
+    Ok(tree)
+}
 
-    fn serialize_i8(self, v: i8) -> Result<()> {
-        self.serialize_i64(i64::from(v))
+// Where the output ends up. Text sinks take the output as written, maps only
+// the variables.
+trait Sink {
+    fn raw(&self) -> bool {
+        false
     }
 
-    fn serialize_i16(self, v: i16) -> Result<()> {
-        self.serialize_i64(i64::from(v))
+    // Whether the sink takes the text along with the variables as they are
+    // once sourced.
+    fn sourced_values(&self) -> bool {
+        false
     }
 
-    fn serialize_i32(self, v: i32) -> Result<()> {
-        self.serialize_i64(i64::from(v))
-    }
+    fn write_text(&mut self, text: &str) -> Result<()>;
 
-    fn serialize_i64(self, v: i64) -> Result<()> {
-        if !self.is_seq {
-            self.output += &(self.keys.join("_") + "=");
-        }
-        self.output += &v.to_string();
-        if !self.is_seq {
-            self.output += "\n";
-        }
+    fn write_key_value(&mut self, _key: &str, _value: &str) -> Result<()> {
         Ok(())
     }
 
-    fn serialize_u8(self, v: u8) -> Result<()> {
-        self.serialize_u64(u64::from(v))
+    // Takes each variable along with the part of the output it was written
+    // to, comments included, before the output is finished.
+    fn write_var(&mut self, _key: &str, _chunk: &str) -> Result<()> {
+        Ok(())
     }
+}
 
-    fn serialize_u16(self, v: u16) -> Result<()> {
-        self.serialize_u64(u64::from(v))
+impl Sink for String {
+    fn write_text(&mut self, text: &str) -> Result<()> {
+        self.push_str(text);
+        Ok(())
     }
+}
 
-    fn serialize_u32(self, v: u32) -> Result<()> {
-        self.serialize_u64(u64::from(v))
+impl Sink for Vec<u8> {
+    fn write_text(&mut self, text: &str) -> Result<()> {
+        self.extend_from_slice(text.as_bytes());
+        Ok(())
     }
+}
 
-    fn serialize_u64(self, v: u64) -> Result<()> {
-        if !self.is_seq {
-            self.output += &(self.keys.join("_") + "=");
-        }
-        self.output += &v.to_string();
+struct WriterSink<W>(W);
 
-        if !self.is_seq {
-            self.output += "\n";
-        }
+impl<W: io::Write> Sink for WriterSink<W> {
+    fn write_text(&mut self, text: &str) -> Result<()> {
+        self.0.write_all(text.as_bytes())?;
         Ok(())
     }
+}
 
-    fn serialize_f32(self, v: f32) -> Result<()> {
-        self.serialize_f64(f64::from(v))
+#[cfg(feature = "json")]
+impl Sink for serde_json::Value {
+    fn raw(&self) -> bool {
+        true
     }
 
-    fn serialize_f64(self, v: f64) -> Result<()> {
-        if !self.is_seq {
-            self.output += &(self.keys.join("_") + "=");
-        }
-        self.output += &v.to_string();
-
-        if !self.is_seq {
-            self.output += "\n";
-        }
+    fn write_text(&mut self, _text: &str) -> Result<()> {
         Ok(())
     }
 
-    fn serialize_char(self, v: char) -> Result<()> {
-        self.serialize_str(&v.to_string())
-    }
+    fn write_key_value(&mut self, key: &str, value: &str) -> Result<()> {
+        use serde_json::{Map, Value};
 
-    // TODO strings with "
-    fn serialize_str(self, v: &str) -> Result<()> {
-        if !self.is_seq {
-            self.output += &(self.keys.join("_") + "=");
+        // Turns a leaf into an object, keeping its value under `""`.
+        fn children(node: &mut Value) -> &mut Map<String, Value> {
+            if !node.is_object() {
+                let mut children = Map::new();
+                if !node.is_null() {
+                    children.insert(String::new(), node.take());
+                }
+                *node = Value::Object(children);
+            }
+            match node {
+                Value::Object(children) => children,
+                _ => unreachable!(),
+            }
         }
-        self.output += "\"";
 
-        self.output += v;
-        self.output += "\"";
-        if !self.is_seq {
-            self.output += "\n";
+        let mut node = self;
+        for segment in key.split('_') {
+            node = children(node)
+                .entry(segment.to_string())
+                .or_insert(Value::Null);
+        }
+        let value = Value::String(value.to_string());
+        match node {
+            Value::Object(children) => {
+                children.insert(String::new(), value);
+            }
+            _ => *node = value,
         }
         Ok(())
     }
+}
 
-    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
-        use serde::ser::SerializeSeq;
-        let mut seq = self.serialize_seq(Some(v.len()))?;
-        for byte in v {
-            seq.serialize_element(byte)?;
-        }
-        seq.end()
+// Both the output as written and the variables as they are once sourced.
+impl Sink for (String, BTreeMap<String, String>) {
+    fn sourced_values(&self) -> bool {
+        true
     }
 
-    fn serialize_none(self) -> Result<()> {
+    fn write_text(&mut self, text: &str) -> Result<()> {
+        self.0.push_str(text);
         Ok(())
     }
 
-    fn serialize_some<T>(self, value: &T) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        value.serialize(self)
-    }
-
-    fn serialize_unit(self) -> Result<()> {
-        self.output += "\"\"";
+    fn write_key_value(&mut self, key: &str, value: &str) -> Result<()> {
+        self.1.insert(key.to_string(), value.to_string());
         Ok(())
     }
+}
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
-        self.serialize_unit()
+impl Sink for BTreeMap<String, String> {
+    // The values are kept as they are once sourced.
+    fn raw(&self) -> bool {
+        true
     }
 
-    fn serialize_unit_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-    ) -> Result<()> {
-        self.serialize_str(variant)
+    fn write_text(&mut self, _text: &str) -> Result<()> {
+        Ok(())
     }
 
-    fn serialize_newtype_struct<T>(
-        self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        value.serialize(self)
+    fn write_key_value(&mut self, key: &str, value: &str) -> Result<()> {
+        self.insert(key.to_string(), value.to_string());
+        Ok(())
     }
+}
 
-    fn serialize_newtype_variant<T>(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        value: &T,
-    ) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        self.output += "{";
-        variant.serialize(&mut *self)?;
-        self.output += ":";
-        value.serialize(&mut *self)?;
-        self.output += "}";
+// The key of each variable along with the part of the output it was written
+// to, see `Sink::write_var`.
+struct VarChunks(Vec<(String, String)>);
+
+impl Sink for VarChunks {
+    fn write_text(&mut self, _text: &str) -> Result<()> {
         Ok(())
     }
 
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        self.is_seq = true;
-        self.output += &(self.keys.join("_") + "=");
-        self.output += "'";
-        Ok(self)
+    fn write_var(&mut self, key: &str, chunk: &str) -> Result<()> {
+        self.0.push((key.to_string(), chunk.to_string()));
+        Ok(())
     }
+}
 
-    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
-        self.serialize_seq(Some(len))
+// Every output goes through here. The `sink` is handed the variables once the
+// whole value serializes, and validates with `validate`, so nothing reaches it
+// on failure.
+fn serialize_into<T, S>(
+    value: &T,
+    mut serializer: Serializer<'_>,
+    sink: &mut S,
+) -> Result<()>
+where
+    T: Serialize,
+    S: Sink,
+{
+    serializer.raw = sink.raw();
+    serializer.sourced_values = sink.sourced_values();
+    value.serialize(&mut serializer)?;
+    if serializer.options.validate {
+        serializer.validate()?;
     }
-
-    // Tuple structs look just like sequences in JSON.
-    fn serialize_tuple_struct(
-        self,
-        _name: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeTupleStruct> {
-        self.serialize_seq(Some(len))
+    let vars = std::mem::take(&mut serializer.vars);
+    let values = std::mem::take(&mut serializer.values);
+    for ((key, _), value) in vars.iter().zip(&values) {
+        sink.write_key_value(key, value)?;
     }
-
-    fn serialize_tuple_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        _len: usize,
-    ) -> Result<Self::SerializeTupleVariant> {
-        self.output += "{";
-        variant.serialize(&mut *self)?;
-        self.output += ":[";
-        Ok(self)
+    for (key, chunk) in var_chunks(&serializer.output, &vars) {
+        sink.write_var(key, chunk)?;
     }
+    sink.write_text(&serializer.finish_with(&vars))
+}
 
-    // Maps are represented in JSON as `{ K: V, K: V, ... }`.
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        Ok(self)
-    }
+// Same as `to_string_with_options`. With `debug_trace`, the key stack and
+// value of each scalar are also written to `trace`, one line as each scalar
+// is serialized, so the lines before a failure are kept. Failing to write to
+// `trace` fails the serialization.
+pub fn to_string_with_trace<T>(
+    value: &T,
+    options: SerializerOptions,
+    trace: &mut dyn io::Write,
+) -> Result<String>
+where
+    T: Serialize,
+{
+    let mut serializer = Serializer::new(options);
+    serializer.trace = Some(trace);
+    let mut output = String::new();
+    serialize_into(value, serializer, &mut output)?;
+    Ok(output)
+}
 
-    // Structs look just like maps in JSON. In particular, JSON requires that we
-    // serialize the field names of the struct. Other formats may be able to
-    // omit the field names when serializing structs because the corresponding
-    // Deserialize implementation is required to know what the keys are without
-    // looking at the serialized data.
-    fn serialize_struct(
-        self,
-        _name: &'static str,
-        len: usize,
-    ) -> Result<Self::SerializeStruct> {
-        self.serialize_map(Some(len))
-    }
+// The keys `value` is serialized to, in order.
+pub fn keys_of<T>(value: &T) -> Result<Vec<String>>
+where
+    T: Serialize,
+{
+    let mut vars = VarChunks(Vec::new());
+    let serializer = Serializer::new(SerializerOptions::default());
+    serialize_into(value, serializer, &mut vars)?;
+    Ok(vars.0.into_iter().map(|(key, _)| key).collect())
+}
 
-    // Struct variants are represented in JSON as `{ NAME: { K: V, ... } }`.
-    // This is the externally tagged representation.
-    fn serialize_struct_variant(
-        self,
-        _name: &'static str,
-        _variant_index: u32,
-        variant: &'static str,
-        _len: usize,
-    ) -> Result<Self::SerializeStructVariant> {
-        self.output += "{";
-        variant.serialize(&mut *self)?;
-        self.output += ":{";
-        Ok(self)
-    }
+// The keys a `T` is serialized to, found by serializing `T::default()`. `None`
+// and unit values are counted in, but maps only have the keys of their
+// default entries, usually none.
+pub fn expected_keys<T>() -> Result<Vec<String>>
+where
+    T: Serialize + Default,
+{
+    let options = SerializerOptions {
+        none_placeholder: Some(String::new()),
+        unit_as_empty_string: true,
+        ..SerializerOptions::default()
+    };
+    let mut vars = VarChunks(Vec::new());
+    serialize_into(&T::default(), Serializer::new(options), &mut vars)?;
+    Ok(vars.0.into_iter().map(|(key, _)| key).collect())
 }
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
-    // Must match the `Ok` type of the serializer.
-    type Ok = ();
-    // Must match the `Error` type of the serializer.
-    type Error = Error;
+// The UTF-8 byte order mark written with `emit_bom`.
+const BOM: char = '\u{feff}';
 
-    // Serialize a single element of the sequence.
-    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        if !self.output.ends_with("'") {
-            self.output += ",";
-        }
-        value.serialize(&mut **self)
-    }
+#[cfg(feature = "sha2")]
+const CHECKSUM_PREFIX: &str = "# sha256=";
 
-    // Close the sequence.
-    fn end(self) -> Result<()> {
-        self.output += "'\n";
-        self.is_seq = false;
-        Ok(())
+// Checks the `# sha256=...` line written with `emit_checksum` against the
+// content before it. Errors with `Error::MissingChecksum` without such a last
+// line, and with `Error::ChecksumMismatch` if the content was changed.
+#[cfg(feature = "sha2")]
+pub fn verify(content: &str) -> Result<()> {
+    let trimmed = content.strip_suffix('\n').unwrap_or(content);
+    let start = trimmed.rfind('\n').map_or(0, |i| i + 1);
+    let checksum = trimmed[start..]
+        .strip_prefix(CHECKSUM_PREFIX)
+        .ok_or(Error::MissingChecksum)?;
+    if sha256_hex(&content[..start]) != checksum {
+        return Err(Error::ChecksumMismatch);
     }
+    Ok(())
 }
 
-// Same thing but for tuples.
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
-
-    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        if !self.output.ends_with('[') {
-            self.output += ",";
-        }
-        value.serialize(&mut **self)
-    }
+#[cfg(feature = "sha2")]
+fn sha256_hex(content: &str) -> String {
+    use sha2::{Digest, Sha256};
 
-    fn end(self) -> Result<()> {
-        self.output += "]";
-        Ok(())
-    }
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
-// Same thing but for tuple structs.
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+// The newtype name `RawValue` serializes under, recognized by the serializer.
+const RAW_VALUE: &str = "$envers::RawValue";
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+// A value written verbatim, without any quoting or escaping, e.g. a shell
+// expression like `$(date)`. It is up to the caller to keep it valid.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawValue(pub String);
+
+impl Serialize for RawValue {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
     where
-        T: ?Sized + Serialize,
+        S: ser::Serializer,
     {
-        if !self.output.ends_with('[') {
-            self.output += ",";
-        }
-        value.serialize(&mut **self)
-    }
-
-    fn end(self) -> Result<()> {
-        self.output += "]";
-        Ok(())
+        serializer.serialize_newtype_struct(RAW_VALUE, &self.0)
     }
 }
 
-// Tuple variants are a little different. Refer back to the
-// `serialize_tuple_variant` method above:
-//
-//    self.output += "{";
-//    variant.serialize(&mut *self)?;
-//    self.output += ":[";
-//
-// So the `end` method in this impl is responsible for closing both the `]` and
-// the `}`.
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+// The newtype name `EnvRef` serializes under, recognized by the serializer.
+const ENV_REF: &str = "$envers::EnvRef";
 
-    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+// A reference to another variable, written `KEY=${OTHER}` so that the shell
+// expands it when sourcing. Sequences are single quoted, so references in
+// them are left as is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvRef(pub String);
+
+impl Serialize for EnvRef {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
     where
-        T: ?Sized + Serialize,
+        S: ser::Serializer,
     {
-        if !self.output.ends_with('[') {
-            self.output += ",";
-        }
-        value.serialize(&mut **self)
-    }
-
-    fn end(self) -> Result<()> {
-        self.output += "]}";
-        Ok(())
+        serializer.serialize_newtype_struct(ENV_REF, &self.0)
     }
 }
 
-// Some `Serialize` types are not able to hold a key and value in memory at the
-// same time so `SerializeMap` implementations are required to support
-// `serialize_key` and `serialize_value` individually.
-//
-// There is a third optional method on the `SerializeMap` trait. The
-// `serialize_entry` method allows serializers to optimize for the case where
-// key and value are both available simultaneously. In JSON it doesn't make a
-// difference so the default behavior for `serialize_entry` is fine.
-impl<'a> ser::SerializeMap for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+// The struct and field names `Tracked` serializes under, recognized by the
+// serializer.
+const TRACKED: &str = "$envers::Tracked";
+const TRACKED_ADDRESS: &str = "$envers::Tracked::address";
+const TRACKED_VALUE: &str = "$envers::Tracked::value";
 
-    // The Serde data model allows map keys to be any serializable type. JSON
-    // only allows string keys so the implementation below will produce invalid
-    // JSON if the key serializes as something other than a string.
-    //
-    // A real JSON serializer would need to validate that map keys are strings.
-    // This can be done by using a different Serializer to serialize the key
-    // (instead of `&mut **self`) and having that other serializer only
-    // implement `serialize_str` and return an error on any other data type.
-    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+// A shared pointer like `Rc` or `Arc`, serialized as the value it points to,
+// whose address is checked by `detect_cycles`. Other serializers see a struct
+// holding the address and the value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tracked<P>(pub P);
+
+impl<P> Serialize for Tracked<P>
+where
+    P: Deref,
+    P::Target: Serialize,
+{
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
     where
-        T: ?Sized + Serialize,
+        S: ser::Serializer,
     {
-        if !self.output.ends_with('{') {
-            self.output += ",";
-        }
-        key.serialize(&mut **self)
+        use serde::ser::SerializeStruct;
+
+        let address = &*self.0 as *const P::Target as *const () as usize;
+        let mut s = serializer.serialize_struct(TRACKED, 2)?;
+        s.serialize_field(TRACKED_ADDRESS, &address)?;
+        s.serialize_field(TRACKED_VALUE, &*self.0)?;
+        s.end()
     }
+}
 
-    // It doesn't make a difference whether the colon is printed at the end of
-    // `serialize_key` or at the beginning of `serialize_value`. In this case
-    // the code is a bit simpler having it here.
-    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
-    where
+// Types namespacing their variables under a prefix of their own, see
+// `to_string_prefixed`.
+pub trait EnvPrefix {
+    const PREFIX: &'static str;
+}
+
+// Same as `to_string`, with every key prefixed by `T::PREFIX`.
+pub fn to_string_prefixed<T>(value: &T) -> Result<String>
+where
+    T: Serialize + EnvPrefix,
+{
+    let options = SerializerOptions {
+        prefix: Some(T::PREFIX.to_string()),
+        ..SerializerOptions::default()
+    };
+    to_string_with_options(value, options)
+}
+
+// Same as `to_string`, keeping only the variables whose full key matches one
+// of the `include` patterns. A pattern matches a key exactly, or any key
+// starting with it when it ends with `*`: `DB_*` matches `DB_HOST`. Patterns
+// are matched case insensitively.
+pub fn to_string_filtered<T>(value: &T, include: &[&str]) -> Result<String>
+where
+    T: Serialize,
+{
+    let patterns: Vec<String> = include
+        .iter()
+        .map(|pattern| pattern.to_uppercase())
+        .collect();
+    let filter = move |keys: &[String]| {
+        let key = keys.join("_");
+        let included =
+            patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => key.starts_with(prefix),
+                    None => key == *pattern,
+                });
+        if included {
+            Some(keys.to_vec())
+        } else {
+            None
+        }
+    };
+    let options = SerializerOptions {
+        key_filter: Some(Box::new(filter)),
+        ..SerializerOptions::default()
+    };
+    to_string_with_options(value, options)
+}
+
+// Same as `to_string`, erroring with `Error::KeyNotAllowed` on the first
+// variable whose name isn't in `allowed`, so that new fields don't reach
+// consumers unnoticed, see `allowed_keys`.
+pub fn to_string_checked<T>(
+    value: &T,
+    allowed: &HashSet<String>,
+) -> Result<String>
+where
+    T: Serialize,
+{
+    let options = SerializerOptions {
+        allowed_keys: Some(allowed.clone()),
+        ..SerializerOptions::default()
+    };
+    to_string_with_options(value, options)
+}
+
+// Same as `to_string`, keeping only the variables that differ from the ones
+// `T::default()` is serialized to. The comparison is done on the serialized
+// variables, so a sequence with a single changed element is written whole.
+pub fn to_string_non_default<T>(value: &T) -> Result<String>
+where
+    T: Serialize + Default + PartialEq,
+{
+    let default = T::default();
+    if *value == default {
+        return Ok(String::new());
+    }
+    let mut defaults = VarChunks(Vec::new());
+    let serializer = Serializer::new(SerializerOptions::default());
+    serialize_into(&default, serializer, &mut defaults)?;
+    let defaults: BTreeMap<String, String> = defaults.0.into_iter().collect();
+
+    let mut vars = VarChunks(Vec::new());
+    let serializer = Serializer::new(SerializerOptions::default());
+    serialize_into(value, serializer, &mut vars)?;
+    Ok(vars
+        .0
+        .into_iter()
+        .filter(|(key, chunk)| defaults.get(key) != Some(chunk))
+        .map(|(_, chunk)| chunk)
+        .collect())
+}
+
+// Serialize each of `values` like `to_string`, joining them with `separator`,
+// e.g. `\n---\n`. `from_str_many` splits them back.
+pub fn to_string_many<T>(values: &[T], separator: &str) -> Result<String>
+where
+    T: Serialize,
+{
+    let documents = values.iter().map(to_string).collect::<Result<Vec<_>>>()?;
+    Ok(documents.join(separator))
+}
+
+// Same as `to_string`, as UTF-8 bytes.
+pub fn to_vec<T>(value: &T) -> Result<Vec<u8>>
+where
+    T: Serialize,
+{
+    let mut output = Vec::new();
+    serialize_into(
+        value,
+        Serializer::new(SerializerOptions::default()),
+        &mut output,
+    )?;
+    Ok(output)
+}
+
+// Serialize to env vars and write them to an `io::Write` with `to_writer`.
+pub fn to_writer<W, T>(writer: W, value: &T) -> Result<()>
+where
+    W: io::Write,
+    T: Serialize,
+{
+    to_writer_with_options(writer, value, SerializerOptions::default())
+}
+
+// Same as `to_writer`, with the given `SerializerOptions`. Nothing is written
+// unless the whole value serializes, and validates with `validate`.
+pub fn to_writer_with_options<W, T>(
+    writer: W,
+    value: &T,
+    options: SerializerOptions,
+) -> Result<()>
+where
+    W: io::Write,
+    T: Serialize,
+{
+    serialize_into(value, Serializer::new(options), &mut WriterSink(writer))
+}
+
+// Same as `to_writer`, but the output goes through a gzip encoder first.
+#[cfg(feature = "flate2")]
+pub fn to_writer_gzip<W, T>(writer: W, value: &T) -> Result<()>
+where
+    W: io::Write,
+    T: Serialize,
+{
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    to_writer(&mut encoder, value)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+// Moves the variables of `output` starting at `vars` so that the keys listed
+// in `order` come first.
+fn reorder(output: &str, vars: &[(String, usize)], order: &[String]) -> String {
+    let mut chunks: Vec<(Option<usize>, &str)> = var_chunks(output, vars)
+        .map(|(key, chunk)| {
+            let rank = order
+                .iter()
+                .position(|field| field.to_uppercase() == key.to_uppercase());
+            (rank, chunk)
+        })
+        .collect();
+    // Stable, so that unlisted variables keep their order
+    chunks.sort_by_key(|(rank, _)| rank.unwrap_or(order.len()));
+    let start = vars.first().map_or(output.len(), |(_, start)| *start);
+    let mut reordered = String::from(&output[..start]);
+    for (_, chunk) in chunks {
+        reordered += chunk;
+    }
+    reordered
+}
+
+// The key of each variable of `output` starting at `vars`, along with the part
+// of `output` it was written to, comments included.
+fn var_chunks<'a>(
+    output: &'a str,
+    vars: &'a [(String, usize)],
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    let ends = vars
+        .iter()
+        .skip(1)
+        .map(|(_, start)| *start)
+        .chain(std::iter::once(output.len()));
+    vars.iter()
+        .zip(ends)
+        .map(move |((key, start), end)| (key.as_str(), &output[*start..end]))
+}
+
+// Whether `name` is a valid shell variable name, e.g. `host` but not `1st`
+// nor `db-host`.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The reserved words of bash, see `prefix_reserved_keywords`.
+const SHELL_KEYWORDS: &[&str] = &[
+    "case", "coproc", "do", "done", "elif", "else", "esac", "fi", "for",
+    "function", "if", "in", "select", "then", "time", "until", "while",
+];
+
+// Removes the leading `prefix` segment of `key`, along with the `joiner`
+// following it.
+fn strip_prefix<'k>(
+    key: &'k str,
+    prefix: Option<&str>,
+    joiner: &str,
+) -> &'k str {
+    match prefix.and_then(|prefix| key.strip_prefix(prefix)) {
+        Some(rest) if rest.starts_with(joiner) => &rest[joiner.len()..],
+        _ => key,
+    }
+}
+
+// Escapes `text` for a properties file: backslashes and line breaks, and in
+// keys the characters that would end them. A leading space of a value would
+// be skipped by the reader, so it is escaped too.
+fn properties_escape(text: &str, key: bool) -> String {
+    let mut escaped = String::new();
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            ':' | '=' | ' ' | '#' | '!' if key => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if i == 0 => escaped += "\\ ",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `'value'`, taken literally by the shell. The only character to escape is
+// `'` itself, by closing the quotes around an escaped one: `'\''`.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Whether `value` is a plain decimal number: an optional `-`, digits and an
+// optional fractional part.
+fn is_numeric(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (digits, None),
+    };
+    let all_digits = |part: &str| {
+        !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())
+    };
+    all_digits(integer) && fraction.is_none_or(all_digits)
+}
+
+// `$'...'` quoting with every character outside of printable ASCII escaped:
+// `\uXXXX`, or `\UXXXXXXXX` past the Basic Multilingual Plane.
+fn ansi_c_quote(value: &str) -> String {
+    let mut quoted = String::from("$'");
+    for c in value.chars() {
+        match c {
+            '\'' => quoted += "\\'",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            ' '..='~' => quoted.push(c),
+            _ if u32::from(c) <= 0xffff => {
+                quoted += &format!("\\u{:04x}", u32::from(c))
+            }
+            _ => quoted += &format!("\\U{:08x}", u32::from(c)),
+        }
+    }
+    quoted += "'";
+    quoted
+}
+
+// Splits `value` with `\` line continuations so that no line is longer than
+// `width`, the first line already holding `offset` characters. Escape
+// sequences like `\"` are never split: the `\` would escape the continuation.
+fn wrap(value: &str, offset: usize, width: usize) -> String {
+    let mut wrapped = String::with_capacity(value.len());
+    let mut line = offset;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let mut token = c.to_string();
+        if c == '\\' {
+            token.extend(chars.next());
+        }
+        let len = token.chars().count();
+        // Keep room for the `\` of the continuation
+        if line + len + 1 > width && line > 0 {
+            wrapped += "\\\n";
+            line = 0;
+        }
+        wrapped += &token;
+        line += len;
+    }
+    wrapped
+}
+
+impl<'t> Serializer<'t> {
+    fn new(options: SerializerOptions) -> Self {
+        let mut serializer = Serializer {
+            output: String::new(),
+            keys: Vec::new(),
+            key: String::new(),
+            key_lens: Vec::new(),
+            root_depth: 0,
+            names: Vec::new(),
+            original: String::new(),
+            original_lens: Vec::new(),
+            originals: BTreeMap::new(),
+            is_seq: false,
+            elements: Vec::new(),
+            chars: String::new(),
+            seq_key: None,
+            pairs: None,
+            vars: Vec::new(),
+            raw: false,
+            values: Vec::new(),
+            sourced_values: false,
+            sourced: None,
+            sourced_elements: Vec::new(),
+            sourced_element: String::new(),
+            map_indices: Vec::new(),
+            variant_indices: Vec::new(),
+            frames: Vec::new(),
+            options_open: Vec::new(),
+            trace: None,
+            blocks: Vec::new(),
+            options,
+        };
+        if let Some(prefix) = serializer.options.prefix.clone() {
+            serializer.push_name(&prefix);
+        }
+        serializer.root_depth = serializer.keys.len();
+        serializer
+    }
+
+    // Checks the variables written, see `SerializerOptions::validate`.
+    fn validate(&self) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for (key, _) in &self.vars {
+            if !is_identifier(key) {
+                return Err(Error::InvalidKey(key.clone()));
+            }
+            if !seen.insert(key) {
+                return Err(Error::DuplicateKey(key.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    // The output once everything is serialized, `vars` having been taken out
+    // of `self` to hand them to the sink.
+    fn finish_with(mut self, vars: &[(String, usize)]) -> String {
+        if let Some(order) = &self.options.field_order {
+            self.output = reorder(&self.output, vars, order);
+        }
+        if self.options.single_line && self.output.ends_with(' ') {
+            self.output.pop();
+        }
+        if self.options.emit_bom {
+            self.output.insert(0, BOM);
+        }
+        #[cfg(feature = "sha2")]
+        if self.options.emit_checksum {
+            if !self.output.is_empty() && !self.output.ends_with('\n') {
+                self.output.push('\n');
+            }
+            let checksum = sha256_hex(&self.output);
+            self.output += CHECKSUM_PREFIX;
+            self.output += &checksum;
+            self.output.push('\n');
+        }
+        self.output
+    }
+
+    fn start_var(&mut self, key: &str) -> Result<()> {
+        if let Some(allowed) = &self.options.allowed_keys {
+            if !allowed.contains(key) {
+                return Err(Error::KeyNotAllowed(key.to_string()));
+            }
+        }
+        if self.case_style() != CaseStyle::Preserve {
+            self.check_case_collision(key)?;
+        }
+        self.vars.push((key.to_string(), self.output.len()));
+        Ok(())
+    }
+
+    // Errors with `Error::CaseCollision` if another variable named `key` was
+    // made from different names, e.g. `fooBar` and `foobar`.
+    fn check_case_collision(&mut self, key: &str) -> Result<()> {
+        match self.originals.get(key) {
+            Some(other)
+                if *other != self.original
+                    && self.cased(other) == self.cased(&self.original) =>
+            {
+                Err(Error::CaseCollision(key.to_string()))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.originals
+                    .insert(key.to_string(), self.original.clone());
+                Ok(())
+            }
+        }
+    }
+
+    // Whether the current key names a path, for `quote_paths`.
+    fn is_path(&self) -> bool {
+        let key = self.key.to_uppercase();
+        key.ends_with("_PATH") || key.ends_with("_DIR")
+    }
+
+    // Writes the `# kind` comment above a variable. Comments would swallow
+    // the rest of the line in `single_line` output, so they are left out.
+    fn annotate(&mut self, kind: &str) {
+        if self.options.annotate_types && !self.options.single_line {
+            self.output += "# ";
+            self.output += kind;
+            self.output += "\n";
+        }
+    }
+
+    // Starts the document of a nested struct with `nested_blocks`: a new
+    // serializer takes over, with the same options, until `close_block`.
+    // The trace goes on in the inner document, and the `Tracked` pointers
+    // being serialized are kept for `detect_cycles`.
+    fn open_block(&mut self) {
+        let mut inner = Serializer::new(SerializerOptions::default());
+        inner.options = std::mem::take(&mut self.options);
+        inner.blocks = std::mem::take(&mut self.blocks);
+        inner.trace = self.trace.take();
+        inner.frames = self
+            .frames
+            .iter()
+            .filter(|frame| matches!(frame, Frame::Tracked(_)))
+            .cloned()
+            .collect();
+        let outer = std::mem::replace(self, inner);
+        self.blocks.push(outer);
+    }
+
+    // Writes the document of the nested struct that just ended as the value
+    // of its key.
+    fn close_block(&mut self) -> Result<()> {
+        let outer = match self.blocks.pop() {
+            Some(outer) => outer,
+            None => return Ok(()),
+        };
+        let mut inner = std::mem::replace(self, outer);
+        self.options = std::mem::take(&mut inner.options);
+        self.blocks = std::mem::take(&mut inner.blocks);
+        self.trace = inner.trace.take();
+        if self.options.validate {
+            inner.validate()?;
+        }
+        let document = inner.output.strip_suffix(self.line_end());
+        let document = document.unwrap_or(&inner.output);
+        if self.raw {
+            return self.emit("block", document);
+        }
+        if self.sourced_values {
+            self.sourced = Some(document.to_string());
+        }
+        self.emit("block", &ansi_c_quote(document))
+    }
+
+    // Whether the struct about to be serialized is below another value, and
+    // not just under the `prefix`.
+    fn is_nested(&self) -> bool {
+        self.keys.len() > self.root_depth
+    }
+
+    // How many structs are being serialized in the current document.
+    fn struct_depth(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| matches!(frame, Frame::Struct(_)))
+            .count()
+    }
+
+    // Writes the `# [field]` comment of `section_headers`, skipped like
+    // `annotate` in `single_line` output.
+    fn section_header(&mut self) {
+        if self.options.single_line || self.is_seq {
+            return;
+        }
+        let name = match (self.names.last(), self.keys.last()) {
+            (Some(Some(name)), _) => name.clone(),
+            (_, Some(segment)) => segment.clone(),
+            _ => return,
+        };
+        self.output += "# [";
+        self.output += &name;
+        self.output += "]\n";
+    }
+
+    // What separates the key from the value.
+    fn assignment(&self) -> &str {
+        self.options.assignment.as_deref().unwrap_or("=")
+    }
+
+    // Starts a `KEY=value` line, up to the value.
+    fn write_key(&mut self, key: &str) {
+        let assignment = self.assignment().to_string();
+        self.output += key;
+        self.output += &assignment;
+    }
+
+    // Ends a `KEY=value` with `line_suffix` and the line terminator.
+    fn end_line(&mut self) {
+        if let Some(suffix) = &self.options.line_suffix {
+            self.output += suffix;
+        }
+        self.output += self.line_end();
+    }
+
+    // What follows each `KEY=value`.
+    fn line_end(&self) -> &'static str {
+        if self.options.single_line {
+            " "
+        } else {
+            "\n"
+        }
+    }
+
+    fn push_key(&mut self, segment: String) {
+        self.push_original(&segment);
+        self.push_segment(segment);
+    }
+
+    fn push_segment(&mut self, segment: String) {
+        self.key_lens.push(self.key.len());
+        self.names.push(None);
+        if !self.keys.is_empty() {
+            self.key += self.joiner(self.keys.len());
+        }
+        self.key += &segment;
+        self.keys.push(segment);
+    }
+
+    // Pushes a prefix, field, variant or map key name, cased according to
+    // `case_style`.
+    fn push_name(&mut self, name: &str) {
+        let segment = self.cased(name);
+        let changed = segment != name;
+        self.push_original(name);
+        self.push_segment(segment);
+        if changed {
+            if let Some(last) = self.names.last_mut() {
+                *last = Some(name.to_string());
+            }
+        }
+    }
+
+    // Appends `name` to `original`, only needed when names are cased.
+    fn push_original(&mut self, name: &str) {
+        if self.case_style() == CaseStyle::Preserve {
+            return;
+        }
+        self.original_lens.push(self.original.len());
+        if !self.keys.is_empty() {
+            self.original += self.joiner(self.keys.len());
+        }
+        self.original += name;
+    }
+
+    // The `case_style` names are actually written with.
+    fn case_style(&self) -> CaseStyle {
+        if self.options.respect_serde_rename_all
+            || self.options.format == Format::DottedProperties
+        {
+            CaseStyle::Preserve
+        } else {
+            self.options.case_style
+        }
+    }
+
+    fn cased(&self, name: &str) -> String {
+        match self.case_style() {
+            CaseStyle::Upper => name.to_uppercase(),
+            CaseStyle::Preserve => name.to_string(),
+        }
+    }
+
+    // Records the address of the `Tracked` pointer being serialized. With
+    // `detect_cycles`, errors if it is already being serialized further up.
+    fn visit<T>(&mut self, address: &T) -> Result<()>
+    where
         T: ?Sized + Serialize,
     {
-        self.output += ":";
-        value.serialize(&mut **self)
+        let address = address.serialize(MapKeySerializer)?;
+        let frame = Frame::Tracked(Some(address));
+        if self.options.detect_cycles && self.frames.contains(&frame) {
+            return Err(Error::CycleDetected(self.key.clone()));
+        }
+        if let Some(last) = self.frames.last_mut() {
+            *last = frame;
+        }
+        Ok(())
     }
 
-    fn end(self) -> Result<()> {
-        self.output += "}";
+    // Prepends `segment` to the path of `err` with `track_path`.
+    fn in_path(&self, err: Error, segment: &str) -> Error {
+        if self.options.track_path {
+            err.in_path(segment)
+        } else {
+            err
+        }
+    }
+
+    // The name the innermost map key was made from, or the index of its
+    // entry with `MapFormat::IndexedEntries`.
+    fn map_key_name(&self) -> String {
+        if let Some((index, true)) = self.map_indices.last() {
+            return index.to_string();
+        }
+        match (self.names.last(), self.keys.last()) {
+            (Some(Some(name)), _) => name.clone(),
+            (_, Some(segment)) => segment.clone(),
+            _ => String::new(),
+        }
+    }
+
+    fn pop_key(&mut self) {
+        self.keys.pop();
+        self.names.pop();
+        if let Some(len) = self.key_lens.pop() {
+            self.key.truncate(len);
+        }
+        if let Some(len) = self.original_lens.pop() {
+            self.original.truncate(len);
+        }
+    }
+
+    // The name of the variable currently being serialized, or `None` if the
+    // `key_filter` drops it.
+    fn key(&self) -> Option<String> {
+        let key = match &self.options.key_filter {
+            Some(filter) => {
+                let key = self.join_keys(&filter(&self.keys)?);
+                let prefix = self.options.strip_prefix.as_deref();
+                strip_prefix(&key, prefix, self.joiner(1)).to_string()
+            }
+            None => self.stripped_key().to_string(),
+        };
+        Some(self.finish_key(key))
+    }
+
+    // Applies `prefix_reserved_keywords`, and escapes the key for
+    // `Format::DottedProperties`.
+    fn finish_key(&self, key: String) -> String {
+        let key = self.prefix_keyword(key);
+        if self.options.format == Format::DottedProperties {
+            return properties_escape(&key, true);
+        }
+        key
+    }
+
+    // Applies `prefix_reserved_keywords` to a variable name.
+    fn prefix_keyword(&self, key: String) -> String {
+        let reserved = SHELL_KEYWORDS
+            .iter()
+            .any(|keyword| keyword.eq_ignore_ascii_case(&key));
+        if !self.options.prefix_reserved_keywords || !reserved {
+            return key;
+        }
+        let prefix = self.options.reserved_keyword_prefix.as_deref();
+        prefix.unwrap_or("VAR_").to_string() + &key
+    }
+
+    fn stripped_key(&self) -> &str {
+        let prefix = self.options.strip_prefix.as_deref();
+        strip_prefix(&self.key, prefix, self.joiner(1))
+    }
+
+    // What goes between the segment `depth` of a key and the ones before,
+    // see `key_joiner`.
+    fn joiner(&self, depth: usize) -> &'static str {
+        match self.options.key_joiner {
+            Some(joiner) => joiner(depth),
+            None if self.options.format == Format::DottedProperties => ".",
+            None => "_",
+        }
+    }
+
+    fn join_keys(&self, keys: &[String]) -> String {
+        let mut key = String::new();
+        for (depth, segment) in keys.iter().enumerate() {
+            if depth > 0 {
+                key += self.joiner(depth);
+            }
+            key += segment;
+        }
+        key
+    }
+
+    // Every scalar ends up here: inside a sequence the value is just one of
+    // the elements, anywhere else it becomes a `KEY=value` line. `kind` names
+    // the serialized type for `annotate_types`.
+    fn emit(&mut self, kind: &str, value: &str) -> Result<()> {
+        if let (true, Some(trace)) = (self.options.debug_trace, &mut self.trace)
+        {
+            writeln!(trace, "{:?} = {}", self.keys, value)?;
+        }
+        let sourced = self.sourced.take();
+        if self.is_seq {
+            self.output += value;
+            if self.sourced_values {
+                self.sourced_element += sourced.as_deref().unwrap_or(value);
+            }
+            return Ok(());
+        }
+        let key = if self.options.key_filter.is_some() {
+            match self.key() {
+                Some(key) => key,
+                None => return Ok(()),
+            }
+        } else {
+            self.finish_key(self.stripped_key().to_string())
+        };
+        self.start_var(&key)?;
+        self.annotate(kind);
+        self.write_key(&key);
+        self.output += value;
+        self.end_line();
+        if self.raw || self.sourced_values {
+            self.values
+                .push(sourced.unwrap_or_else(|| value.to_string()));
+        }
         Ok(())
     }
-}
 
-// Structs are like maps in which the keys are constrained to be compile-time
-// constant strings.
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    // Whether the value being serialized is directly inside a `Some`, e.g. the
+    // inner option of an `Option<Option<T>>`.
+    fn in_option(&self) -> bool {
+        self.options_open.last() == Some(&self.keys.len())
+    }
+
+    // Writes the `presence_flag_suffix` flag of the `Option` under the
+    // current key.
+    fn emit_presence_flag(&mut self, present: bool) -> Result<()> {
+        let suffix = match &self.options.presence_flag_suffix {
+            Some(suffix) if !self.is_seq => suffix.clone(),
+            _ => return Ok(()),
+        };
+        self.push_name(&suffix);
+        let result = self.emit_scalar("bool", &present.to_string());
+        self.pop_key();
+        result
+    }
+
+    // Bools and numbers, left unquoted unless `quote_all` is set.
+    fn emit_scalar(&mut self, kind: &str, value: &str) -> Result<()> {
+        if self.options.quote_all {
+            return self.emit_str(kind, value);
+        }
+        self.emit(kind, value)
+    }
+
+    // TODO strings with " are only handled by `Quoting::Safe`
+    fn emit_str(&mut self, kind: &str, v: &str) -> Result<()> {
+        let multiline = v.contains(['\n', '\r']);
+        if multiline && self.options.forbid_multiline_values {
+            return Err(Error::MultilineValue(self.key.clone()));
+        }
+        if self.raw {
+            return self.emit(kind, v);
+        }
+        if self.sourced_values {
+            self.sourced = Some(v.to_string());
+        }
+        if self.options.format == Format::DottedProperties {
+            return self.emit(kind, &properties_escape(v, false));
+        }
+        if self.options.format == Format::DockerEnvFile {
+            if multiline {
+                return Err(Error::MultilineValue(self.key.clone()));
+            }
+            return self.emit(kind, v);
+        }
+
+        if self.options.quote_paths && !self.is_seq && self.is_path() {
+            return self.emit(kind, &single_quote(v));
+        }
+        if self.is_seq && self.options.unquoted_seq_elements {
+            return self.emit(kind, v);
+        }
+        let quote_all = self.options.quote_all;
+        if self.options.unquoted_numeric_strings && !quote_all && is_numeric(v)
+        {
+            return self.emit(kind, v);
+        }
+        if self.options.ascii_escape_values && !self.is_seq && !v.is_ascii() {
+            return self.emit(kind, &ansi_c_quote(v));
+        }
+
+        let mut quoted = self.quote(v);
+        if let Some(width) = self.options.wrap_width {
+            if !self.is_seq && self.options.quoting != Quoting::Printf {
+                if let Some(key) = self.key() {
+                    let offset =
+                        key.chars().count() + self.assignment().chars().count();
+                    quoted = wrap(&quoted, offset, width);
+                }
+            }
+        }
+        self.emit(kind, &quoted)
+    }
+
+    // Writes a float, or applies `nonfinite` to NaN and infinities.
+    fn emit_float<F>(&mut self, kind: &str, v: F, finite: bool) -> Result<()>
+    where
+        F: std::fmt::Display
+            + std::fmt::LowerExp
+            + std::str::FromStr
+            + PartialEq,
+    {
+        if finite {
+            let formatted = self.format_float(v)?;
+            return self.emit_scalar(kind, &formatted);
+        }
+        match self.options.nonfinite {
+            NonFinitePolicy::Error => {
+                Err(Error::NonFiniteFloat(self.key.clone()))
+            }
+            NonFinitePolicy::AsString => self.emit_str(kind, &v.to_string()),
+            NonFinitePolicy::AsNull => Ok(()),
+        }
+    }
+
+    // `f32` are formatted as `f32`: going through `f64` would print the
+    // widening error, e.g. `1.100000023841858` for `1.1`.
+    fn format_float<F>(&self, v: F) -> Result<String>
+    where
+        F: std::fmt::Display
+            + std::fmt::LowerExp
+            + std::str::FromStr
+            + PartialEq,
+    {
+        let precision =
+            match (self.options.float_precision, self.options.float_format) {
+                (Some(precision), _)
+                | (None, FloatFormat::FixedDecimals(precision)) => precision,
+                (None, FloatFormat::Shortest) => return Ok(v.to_string()),
+                (None, FloatFormat::Scientific) => {
+                    return Ok(format!("{:e}", v))
+                }
+            };
+        let formatted = format!("{:.*}", precision, v);
+        if self.options.error_on_precision_loss
+            && formatted.parse::<F>().ok() != Some(v)
+        {
+            return Err(Error::PrecisionLoss(self.key.clone()));
+        }
+        Ok(formatted)
+    }
+
+    fn quote(&self, v: &str) -> String {
+        let mut quoted = String::new();
+        match self.options.quoting {
+            Quoting::Double => {
+                quoted += "\"";
+                quoted += v;
+                quoted += "\"";
+            }
+            Quoting::Safe => {
+                quoted += "\"";
+                for c in v.chars() {
+                    if let '"' | '\\' | '$' | '`' = c {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                }
+                quoted += "\"";
+            }
+            Quoting::None => quoted += v,
+            Quoting::Printf => {
+                quoted += "$(printf '";
+                for byte in v.bytes() {
+                    match byte {
+                        b'%' => quoted += "%%",
+                        b'\\' => quoted += "\\\\",
+                        b'\'' => quoted += "\\047",
+                        b' '..=b'~' => quoted.push(char::from(byte)),
+                        _ => quoted += &format!("\\{:03o}", byte),
+                    }
+                }
+                quoted += "')";
+            }
+        }
+        quoted
+    }
+
+    // Sequences, tuples and tuple structs all end up as `KEY='elem,elem'`.
+    fn push_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let start = self.output.len();
+        let sourced_start = self.sourced_element.len();
+        let index = self.elements.len();
+        value
+            .serialize(&mut *self)
+            .map_err(|err| self.in_path(err, &index.to_string()))?;
+        let element = self.output.split_off(start);
+        self.elements.push(element);
+        if self.sourced_values {
+            let sourced = self.sourced_element.split_off(sourced_start);
+            self.sourced_elements.push(sourced);
+        }
+        Ok(())
+    }
+
+    // With `treat_pair_seq_as_map`, the first element decides whether the
+    // sequence is written as a map or as usual. Once it is a map, elements
+    // that aren't 2-tuples error with `Error::UnsupportedNesting`.
+    fn push_pair<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        // The value of a pair may be a sequence itself, overwriting these.
+        let pairs = self.pairs;
+        let seq_key = self.seq_key.take();
+        let mut matched = false;
+        self.is_seq = false;
+        let result = value.serialize(PairSerializer {
+            ser: &mut *self,
+            matched: &mut matched,
+        });
+        self.pairs = pairs;
+        self.seq_key = seq_key;
+        match (matched, pairs) {
+            (false, None) => {
+                self.pairs = Some(false);
+                self.is_seq = true;
+                self.push_element(value)
+            }
+            (false, _) => result,
+            (true, _) => {
+                self.pairs = Some(true);
+                result
+            }
+        }
+    }
+
+    fn end_elements(&mut self, sort: bool) -> Result<()> {
+        let chars = std::mem::take(&mut self.chars);
+        let count = self.elements.len();
+        if self.options.chars_as_string
+            && count > 0
+            && chars.chars().count() == count
+        {
+            return self.end_chars(chars, sort);
+        }
+        if sort {
+            self.sort_elements();
+        }
+        let empty_seq = self.options.empty_seq;
+        if count == 0 && empty_seq == EmptySeqPolicy::Omit {
+            self.seq_key = None;
+        }
+        let sourced = std::mem::take(&mut self.sourced_elements).join(",");
+        if let Some(key) = self.seq_key.take() {
+            self.start_var(&key)?;
+            self.annotate("seq");
+            let quotes = match self.options.format {
+                _ if self.raw => ("", ""),
+                Format::Bash
+                    if count == 0
+                        && empty_seq == EmptySeqPolicy::EmptyParens =>
+                {
+                    ("(", ")")
+                }
+                Format::Bash => ("'", "'"),
+                Format::DockerEnvFile | Format::DottedProperties => ("", ""),
+            };
+            let value = self.elements.join(",");
+            self.write_key(&key);
+            self.output += quotes.0;
+            self.output += &value;
+            self.output += quotes.1;
+            self.end_line();
+            if self.raw {
+                self.values.push(value);
+            } else if self.sourced_values {
+                self.values.push(sourced);
+            }
+        }
+        self.elements.clear();
+        self.is_seq = false;
+        Ok(())
+    }
+
+    // Sorts the elements by their serialized form, along with their sourced
+    // values.
+    fn sort_elements(&mut self) {
+        if !self.sourced_values {
+            self.elements.sort();
+            return;
+        }
+        let elements = std::mem::take(&mut self.elements);
+        let sourced = std::mem::take(&mut self.sourced_elements);
+        let mut pairs: Vec<_> = elements.into_iter().zip(sourced).collect();
+        pairs.sort();
+        (self.elements, self.sourced_elements) = pairs.into_iter().unzip();
+    }
+
+    // With `chars_as_string`, a sequence of chars is written as a string.
+    fn end_chars(&mut self, chars: String, sort: bool) -> Result<()> {
+        let mut chars: Vec<char> = chars.chars().collect();
+        if sort {
+            chars.sort();
+        }
+        self.elements.clear();
+        self.sourced_elements.clear();
+        self.is_seq = false;
+        if self.seq_key.take().is_none() {
+            return Ok(());
+        }
+        self.emit_str("chars", &chars.into_iter().collect::<String>())
+    }
+}
+
+impl ser::Serializer for &mut Serializer<'_> {
+    type Ok = ();
+
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    // Elements of sequences are always written, whatever `skip_false_bools`
+    // or `FlagStyle::Presence`.
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        let style = self.options.flag_style;
+        let skip =
+            self.options.skip_false_bools || style == FlagStyle::Presence;
+        if !v && skip && !self.is_seq {
+            return Ok(());
+        }
+        let value = match (style, v) {
+            (FlagStyle::TrueFalse, true) => "true",
+            (FlagStyle::TrueFalse, false) => "false",
+            (_, true) => "1",
+            (_, false) => "0",
+        };
+        self.emit_scalar("bool", value)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.emit_scalar("int8", &v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.emit_scalar("int16", &v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.emit_scalar("int32", &v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.emit_scalar("int64", &v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.emit_scalar("uint8", &v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.emit_scalar("uint16", &v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.emit_scalar("uint32", &v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.emit_scalar("uint64", &v.to_string())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.emit_scalar("int128", &v.to_string())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.emit_scalar("uint128", &v.to_string())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.emit_float("float32", v, v.is_finite())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.emit_float("float64", v, v.is_finite())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        if self.is_seq {
+            self.chars.push(v);
+        }
+        self.emit_str("char", &v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.emit_str("string", v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        use serde::ser::SerializeSeq;
+        if self.options.bytes_encoding == BytesEncoding::Utf8 {
+            let v = std::str::from_utf8(v)
+                .map_err(|_| Error::InvalidUtf8(self.key.clone()))?;
+            return self.emit_str("bytes", v);
+        }
+        let mut seq = self.serialize_seq(Some(v.len()))?;
+        for byte in v {
+            seq.serialize_element(byte)?;
+        }
+        seq.end()
+    }
+
+    // `None` is omitted, unless `none_placeholder` gives it a value.
+    fn serialize_none(self) -> Result<()> {
+        if let Some(placeholder) = self.options.none_placeholder.clone() {
+            self.emit_str("none", &placeholder)?;
+        }
+        if self.in_option() {
+            return Ok(());
+        }
+        self.emit_presence_flag(false)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let outermost = !self.in_option();
+        self.options_open.push(self.keys.len());
+        let result = value.serialize(&mut *self);
+        self.options_open.pop();
+        result?;
+        if !outermost {
+            return Ok(());
+        }
+        self.emit_presence_flag(true)
+    }
+
+    // Unit carries no information, so there is nothing to write unless
+    // `unit_as_empty_string` asks for it.
+    fn serialize_unit(self) -> Result<()> {
+        if self.options.unit_as_empty_string {
+            self.emit("unit", "\"\"")?;
+        }
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        if self.options.enum_as_index {
+            return self.emit_scalar("enum", &variant_index.to_string());
+        }
+        self.emit_str("enum", variant)
+    }
+
+    // A `RawValue` is written as is, an `EnvRef` as `${NAME}`, other newtypes
+    // as their inner value.
+    fn serialize_newtype_struct<T>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if name == RAW_VALUE {
+            let raw = value.serialize(MapKeySerializer)?;
+            return self.emit("raw", &raw);
+        }
+        if name == ENV_REF {
+            let name = value.serialize(MapKeySerializer)?;
+            return self.emit("ref", &format!("${{{}}}", name));
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_name(variant);
+        value.serialize(&mut *self)?;
+        self.pop_key();
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        if self.options.scalars_only {
+            return Err(Error::UnsupportedNesting(self.key.clone()));
+        }
+        self.chars.clear();
+        self.is_seq = true;
+        self.seq_key = self.key();
+        self.pairs = None;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    // Tuple structs look just like sequences.
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    // Tuple variants are flattened like struct variants, their fields named
+    // by their index: `KEY_VARIANT_0=value`.
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        self.push_name(variant);
+        self.variant_indices.push(0);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        if self.options.scalars_only {
+            return Err(Error::UnsupportedNesting(self.key.clone()));
+        }
+        if self.options.map_format == MapFormat::IndexedEntries {
+            self.map_indices.push((0, false));
+        }
+        Ok(self)
+    }
+
+    // Structs are flattened: each field name is pushed on the keys stack, so
+    // nested fields end up as `PARENT_CHILD=value`.
+    //
+    // With `fold_single_field_structs`, nested structs with a single field
+    // don't add its name: `WRAPPER_INNER=value` becomes `WRAPPER=value`.
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct> {
+        if name == TRACKED {
+            self.frames.push(Frame::Tracked(None));
+            return Ok(self);
+        }
+        let fold = self.options.fold_single_field_structs
+            && len == 1
+            && self.is_nested();
+        if self.options.section_headers && self.struct_depth() == 1 {
+            self.section_header();
+        }
+        if self.options.nested_blocks && self.is_nested() && !self.is_seq {
+            self.open_block();
+        }
+        self.frames.push(Frame::Struct(fold));
+        Ok(self)
+    }
+
+    // Struct variants are flattened like structs, under the variant name:
+    // `KEY_VARIANT_FIELD=value`. This is the externally tagged representation.
+    // Without any field there would be nothing left of the variant, so it is
+    // written like a unit variant instead: `KEY="Variant"`.
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        if len == 0 {
+            self.emit_str("enum", variant)?;
+        }
+        self.push_name(variant);
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer<'_> {
+    // Must match the `Ok` type of the serializer.
+    type Ok = ();
+    // Must match the `Error` type of the serializer.
+    type Error = Error;
+
+    // Serialize a single element of the sequence.
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if self.options.treat_pair_seq_as_map && self.pairs != Some(false) {
+            return self.push_pair(value);
+        }
+        self.push_element(value)
+    }
+
+    // Close the sequence. A sequence written as a map has nothing left to
+    // write.
+    fn end(self) -> Result<()> {
+        if self.pairs == Some(true) {
+            self.seq_key = None;
+        }
+        self.pairs = None;
+        let sort = self.options.sort_seqs;
+        self.end_elements(sort)
+    }
+}
+
+// Same thing but for tuples. Their elements are positional, so they are never
+// sorted.
+impl ser::SerializeTuple for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_elements(false)
+    }
+}
+
+// Same thing but for tuple structs.
+impl ser::SerializeTupleStruct for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_elements(false)
+    }
+}
+
+// Tuple variants are sequences under the variant name, see
+// `serialize_tuple_variant`. The `end` method is responsible for popping that
+// name once the sequence is written.
+impl ser::SerializeTupleVariant for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let index = self.variant_indices.last_mut().map_or(0, |index| {
+            *index += 1;
+            *index - 1
+        });
+        self.push_key(index.to_string());
+        value
+            .serialize(&mut **self)
+            .map_err(|err| self.in_path(err, &index.to_string()))?;
+        self.pop_key();
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.variant_indices.pop();
+        self.pop_key();
+        Ok(())
+    }
+}
+
+// Maps are flattened like structs: each key becomes a segment of the variable
+// names, `MAP_KEY=value`. Keys are uppercased like everything else, so only
+// maps whose keys are already uppercase and free of `_` deserialize back to
+// the same keys: `host` comes back as `HOST`, and `MY_KEY` as a nested `MY`.
+impl ser::SerializeMap for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    // The Serde data model allows map keys to be any serializable type, but
+    // only the ones that can be written as a single segment of a variable name
+    // are accepted by the `MapKeySerializer`.
+    // With `MapFormat::IndexedEntries`, keys that aren't identifiers are
+    // written as a value under the index of the entry instead.
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if let Some(entry) = self.map_indices.last_mut() {
+            match key.serialize(MapKeySerializer) {
+                Ok(segment) if is_identifier(&segment) => {
+                    entry.1 = false;
+                    self.push_name(&segment);
+                    return Ok(());
+                }
+                _ => entry.1 = true,
+            }
+            let index = entry.0;
+            self.push_key(index.to_string());
+            self.push_key(String::from("KEY"));
+            key.serialize(&mut **self)?;
+            self.pop_key();
+            self.push_key(String::from("VALUE"));
+            return Ok(());
+        }
+        let segment = key.serialize(MapKeySerializer)?;
+        self.push_name(&segment);
+        Ok(())
+    }
+
+    // The key pushed by `serialize_key` is popped once the value is written.
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value
+            .serialize(&mut **self)
+            .map_err(|err| self.in_path(err, &self.map_key_name()))?;
+        self.pop_key();
+        if let Some((index, true)) = self.map_indices.last_mut() {
+            *index += 1;
+            self.pop_key();
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.map_indices.pop();
+        Ok(())
+    }
+}
+
+// Structs are like maps in which the keys are constrained to be compile-time
+// constant strings.
+impl ser::SerializeStruct for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if key == TRACKED_ADDRESS {
+            return self.visit(value);
+        }
+        if key == TRACKED_VALUE {
+            return value.serialize(&mut **self);
+        }
+        if self.frames.last() == Some(&Frame::Struct(true)) {
+            return value
+                .serialize(&mut **self)
+                .map_err(|err| self.in_path(err, key));
+        }
+        self.push_name(key);
+        value
+            .serialize(&mut **self)
+            .map_err(|err| self.in_path(err, key))?;
+        self.pop_key();
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        let frame = self.frames.pop();
+        if let Some(Frame::Struct(_)) = frame {
+            if self.struct_depth() == 0 && !self.blocks.is_empty() {
+                self.close_block()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.push_name(key);
+        value
+            .serialize(&mut **self)
+            .map_err(|err| self.in_path(err, key))?;
+        self.pop_key();
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.pop_key();
+        Ok(())
+    }
+}
+
+// Turns a map key into a segment of the variable names. Strings, chars,
+// integers and unit variants are accepted, anything else errors with
+// `Error::InvalidMapKey`.
+struct MapKeySerializer;
+
+impl ser::Serializer for MapKeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<String, Error>;
+    type SerializeTuple = ser::Impossible<String, Error>;
+    type SerializeTupleStruct = ser::Impossible<String, Error>;
+    type SerializeTupleVariant = ser::Impossible<String, Error>;
+    type SerializeMap = ser::Impossible<String, Error>;
+    type SerializeStruct = ser::Impossible<String, Error>;
+    type SerializeStructVariant = ser::Impossible<String, Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_char(self, v: char) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(Error::InvalidMapKey)
+    }
+
+    // Enum keys use the variant name.
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct> {
+        Err(Error::InvalidMapKey)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::InvalidMapKey)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Writes an element of a sequence of 2-tuples as a map entry, see
+// `treat_pair_seq_as_map`. Anything but a 2-tuple errors without writing
+// anything, leaving `matched` to `false`.
+struct PairSerializer<'a, 't> {
+    ser: &'a mut Serializer<'t>,
+    matched: &'a mut bool,
+}
+
+impl PairSerializer<'_, '_> {
+    fn not_pair(&self) -> Error {
+        Error::UnsupportedNesting(self.ser.key.clone())
+    }
+}
+
+impl<'a, 't> ser::Serializer for PairSerializer<'a, 't> {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<(), Error>;
+    type SerializeTuple = PairEntry<'a, 't>;
+    type SerializeTupleStruct = ser::Impossible<(), Error>;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = ser::Impossible<(), Error>;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_char(self, _v: char) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_str(self, _v: &str) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(self.not_pair())
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        _value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(self.not_pair())
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        Err(self.not_pair())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        if len != 2 {
+            return Err(self.not_pair());
+        }
+        *self.matched = true;
+        Ok(PairEntry {
+            ser: self.ser,
+            index: 0,
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct> {
+        Err(self.not_pair())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(self.not_pair())
+    }
+}
+
+// The first element of the tuple is pushed as a key segment, like map keys,
+// and popped once the second one is written.
+struct PairEntry<'a, 't> {
+    ser: &'a mut Serializer<'t>,
+    index: usize,
+}
+
+impl ser::SerializeTuple for PairEntry<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if self.index == 0 {
+            let segment = value.serialize(MapKeySerializer)?;
+            self.ser.push_name(&segment);
+        } else {
+            value.serialize(&mut *self.ser)?;
+        }
+        self.index += 1;
+        Ok(())
+    }
+
+    fn end(self) -> Result<()> {
+        self.ser.pop_key();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        expected_keys, keys_of, to_map, to_string, to_string_and_map,
+        to_string_checked, to_string_filtered, to_string_many,
+        to_string_non_default, to_string_prefixed, to_string_with_options,
+        to_string_with_trace, to_vec, to_writer, to_writer_with_options,
+        BytesEncoding, CaseStyle, EmptySeqPolicy, EnvPrefix, EnvRef, FlagStyle,
+        FloatFormat, Format, MapFormat, NonFinitePolicy, Quoting, RawValue,
+        Serializer, SerializerOptions, Tracked,
+    };
+    use crate::error::Error;
+    use serde_derive::Serialize;
+    use std::collections::{BTreeMap, HashSet};
+    use std::io;
+
+    #[test]
+    fn test_struct() {
+        #[derive(Serialize)]
+        struct Test {
+            uint8: u8,
+            int8: i8,
+            uint16: u16,
+            int16: i16,
+            uint32: u32,
+            int32: i32,
+            uint64: u64,
+            int64: i64,
+            float32: f32,
+            float64: f64,
+            character: char,
+            string: String,
+        }
+
+        let test = Test {
+            uint8: 1,
+            int8: 1,
+            uint16: 1,
+            int16: 1,
+            uint32: 1,
+            int32: 1,
+            uint64: 1,
+            int64: 1,
+            float32: 1.0,
+            float64: 1.0,
+            character: 'c',
+            string: String::from("s"),
+        };
+        let expected = "UINT8=1\nINT8=1\nUINT16=1\nINT16=1\nUINT32=1\nINT32=1\nUINT64=1\nINT64=1\nFLOAT32=1\nFLOAT64=1\nCHARACTER=\"c\"\nSTRING=\"s\"\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        // The default options must keep producing the output above
+        let options = SerializerOptions::default();
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_seq() {
+        #[derive(Serialize)]
+        struct Test {
+            seq: Vec<&'static str>,
+        }
+        let test = Test {
+            seq: vec!["a", "b"],
+        };
+        let expected = "SEQ='\"a\",\"b\"'\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        // When we have a simple seq, we can't name the key properly
+        let test = vec!["a", "b"];
+        let expected = "='\"a\",\"b\"'\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_negative_seq() {
+        #[derive(Serialize)]
+        struct Test {
+            seq: Vec<i64>,
+            floats: Vec<f64>,
+        }
+
+        let test = vec![-1i64, 2, -3];
+        assert_eq!(to_string(&test).unwrap(), "='-1,2,-3'\n");
+
+        let test = Test {
+            seq: vec![-1, 2, -3],
+            floats: vec![-1.5],
+        };
+        let expected = "SEQ='-1,2,-3'\nFLOATS='-1.5'\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_integer_bounds() {
+        #[derive(Serialize)]
+        struct Test {
+            min: i64,
+            max: i64,
+            umax: u64,
+            seq: Vec<i64>,
+            useq: Vec<u64>,
+        }
+
+        let test = Test {
+            min: i64::MIN,
+            max: i64::MAX,
+            umax: u64::MAX,
+            seq: vec![i64::MIN, i64::MAX],
+            useq: vec![u64::MAX],
+        };
+        let expected = "MIN=-9223372036854775808\n\
+                        MAX=9223372036854775807\n\
+                        UMAX=18446744073709551615\n\
+                        SEQ='-9223372036854775808,9223372036854775807'\n\
+                        USEQ='18446744073709551615'\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        // Integers are never quoted, whatever the quoting
+        let options = SerializerOptions {
+            quoting: Quoting::Safe,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_integer_seqs() {
+        #[derive(Serialize)]
+        struct Test {
+            int8: Vec<i8>,
+            int128: Vec<i128>,
+            uint128: Vec<u128>,
+            scalar: u128,
+        }
+
+        let test = Test {
+            int8: vec![-128, 0, 127],
+            int128: vec![i128::MIN],
+            uint128: vec![u128::MAX, 1],
+            scalar: u128::MAX,
+        };
+        let expected = "INT8='-128,0,127'\n\
+                        INT128='-170141183460469231731687303715884105728'\n\
+                        UINT128='340282366920938463463374607431768211455,1'\n\
+                        SCALAR=340282366920938463463374607431768211455\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_unquoted_seq_elements() {
+        #[derive(Serialize)]
+        struct Test {
+            seq: Vec<&'static str>,
+            string: &'static str,
+        }
+
+        let test = Test {
+            seq: vec!["a", "b"],
+            string: "s",
+        };
+        let expected = "SEQ='\"a\",\"b\"'\nSTRING=\"s\"\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let options = SerializerOptions {
+            unquoted_seq_elements: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "SEQ='a,b'\nSTRING=\"s\"\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sorted_set() {
+        use std::collections::HashSet;
+
+        #[derive(Serialize)]
+        struct Test {
+            set: HashSet<String>,
+        }
+
+        let test = Test {
+            set: ["c", "a", "d", "b"].iter().map(|s| s.to_string()).collect(),
+        };
+        let options = || SerializerOptions {
+            sort_seqs: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "SET='\"a\",\"b\",\"c\",\"d\"'\n";
+        assert_eq!(to_string_with_options(&test, options()).unwrap(), expected);
+
+        // A new set iterates in a new order, the output must not change.
+        let test = Test {
+            set: ["d", "b", "a", "c"].iter().map(|s| s.to_string()).collect(),
+        };
+        assert_eq!(to_string_with_options(&test, options()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_array() {
+        #[derive(Serialize)]
+        struct Test {
+            array: [u16; 3],
+            tuple: (u8, String),
+        }
+
+        let test = Test {
+            array: [3, 1, 2],
+            tuple: (1, String::from("a")),
+        };
+        let options = SerializerOptions {
+            sort_seqs: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "ARRAY='3,1,2'\nTUPLE='1,\"a\"'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_scalars_only() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            seq: vec![1, 2],
+        };
+        let options = SerializerOptions {
+            scalars_only: true,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap_err(),
+            Error::UnsupportedNesting(String::from("SEQ"))
+        );
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        #[derive(Serialize)]
+        struct Test {
+            config: Config,
+            configured: bool,
+        }
+
+        #[derive(Serialize)]
+        struct Config {
+            host: String,
+            port: u16,
+        }
+
+        let test = Test {
+            config: Config {
+                host: String::from("localhost"),
+                port: 80,
+            },
+            configured: true,
+        };
+        let options = SerializerOptions {
+            strip_prefix: Some(String::from("CONFIG")),
+            ..SerializerOptions::default()
+        };
+        let expected = "HOST=\"localhost\"\nPORT=80\nCONFIGURED=true\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_prefix() {
+        #[derive(Serialize)]
+        struct Test {
+            host: String,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            port: u16,
+        }
+
+        impl EnvPrefix for Test {
+            const PREFIX: &'static str = "app";
+        }
+
+        let test = Test {
+            host: String::from("x"),
+            nested: Nested { port: 80 },
+        };
+        let expected = "APP_HOST=\"x\"\nAPP_NESTED_PORT=80\n";
+        assert_eq!(to_string_prefixed(&test).unwrap(), expected);
+
+        let options = SerializerOptions {
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_prefix_map_seq() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize)]
+        struct Test {
+            db: HashMap<&'static str, &'static str>,
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            db: vec![("host", "x")].into_iter().collect(),
+            seq: vec![1, 2],
+        };
+        let options = SerializerOptions {
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        let expected = "APP_DB_HOST=\"x\"\nAPP_SEQ='1,2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Top level maps and sequences are prefixed too
+        let map: HashMap<_, _> = vec![("host", 1)].into_iter().collect();
+        let options = SerializerOptions {
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&map, options).unwrap(),
+            "APP_HOST=1\n"
+        );
+
+        let options = SerializerOptions {
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&vec![1], options).unwrap(),
+            "APP='1'\n"
+        );
+    }
+
+    #[test]
+    fn test_quoting() {
+        #[derive(Serialize)]
+        struct Test {
+            string: String,
+        }
+
+        let test = Test {
+            string: String::from("say \"$HOME\""),
+        };
+        let options = |quoting| SerializerOptions {
+            quoting,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options(Quoting::Double)).unwrap(),
+            "STRING=\"say \"$HOME\"\"\n"
+        );
+        assert_eq!(
+            to_string_with_options(&test, options(Quoting::Safe)).unwrap(),
+            "STRING=\"say \\\"\\$HOME\\\"\"\n"
+        );
+        assert_eq!(
+            to_string_with_options(&test, options(Quoting::None)).unwrap(),
+            "STRING=say \"$HOME\"\n"
+        );
+    }
+
+    #[test]
+    fn test_forbid_multiline_values() {
+        #[derive(Serialize)]
+        struct Test {
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            string: &'static str,
+        }
+
+        let options = SerializerOptions {
+            forbid_multiline_values: true,
+            ..SerializerOptions::default()
+        };
+        let test = Test {
+            nested: Nested { string: "a\r\nb" },
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap_err(),
+            Error::MultilineValue(String::from("NESTED_STRING"))
+        );
+
+        let options = SerializerOptions {
+            forbid_multiline_values: true,
+            ..SerializerOptions::default()
+        };
+        let test = Test {
+            nested: Nested { string: "a b" },
+        };
+        let expected = "NESTED_STRING=\"a b\"\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_quote_paths() {
+        #[derive(Serialize)]
+        struct Test {
+            config_path: &'static str,
+            data_dir: &'static str,
+            name: &'static str,
+        }
+
+        let test = Test {
+            config_path: "~/$HOME/it's.toml",
+            data_dir: "/data",
+            name: "$HOME",
+        };
+        let options = SerializerOptions {
+            quote_paths: true,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        let expected = "CONFIG_PATH='~/$HOME/it'\\''s.toml'\n\
+                        DATA_DIR='/data'\n\
+                        NAME=\"$HOME\"\n";
+        assert_eq!(output, expected);
+
+        // Sourcing the output gives back the exact path.
+        #[cfg(unix)]
+        {
+            let script = format!("{}printf '%s' \"$CONFIG_PATH\"", output);
+            let sourced = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .output()
+                .unwrap();
+            assert_eq!(sourced.stdout, test.config_path.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_quoting_printf() {
+        #[derive(Serialize)]
+        struct Test {
+            string: String,
+        }
+
+        let test = Test {
+            string: String::from("a\u{1}b'%\\é\nc"),
+        };
+        let options = SerializerOptions {
+            quoting: Quoting::Printf,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert_eq!(
+            output,
+            "STRING=$(printf 'a\\001b\\047%%\\\\\\303\\251\\012c')\n"
+        );
+
+        // Sourcing the output gives back the exact bytes.
+        #[cfg(unix)]
+        {
+            let script = format!("{}printf '%s' \"$STRING\"", output);
+            let sourced = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .output()
+                .unwrap();
+            assert_eq!(sourced.stdout, test.string.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_ascii_escape_values() {
+        #[derive(Serialize)]
+        struct Test {
+            emoji: String,
+            text: String,
+            seq: Vec<String>,
+        }
+
+        let test = Test {
+            emoji: String::from("it's \u{1f600} \\ café"),
+            text: String::from("plain"),
+            seq: vec![String::from("é")],
+        };
+        let options = SerializerOptions {
+            ascii_escape_values: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "EMOJI=$'it\\'s \\U0001f600 \\\\ caf\\u00e9'\n\
+                        TEXT=\"plain\"\n\
+                        SEQ='\"é\"'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[cfg(feature = "rust_decimal")]
+    #[test]
+    fn test_decimal() {
+        use rust_decimal::Decimal;
+        use serde_derive::Deserialize;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            price: Decimal,
+            name: String,
+        }
+
+        let test = Test {
+            price: Decimal::new(-150, 2),
+            name: String::from("1.0.0"),
+        };
+        let output = to_string(&test).unwrap();
+        assert_eq!(output, "PRICE=\"-1.50\"\nNAME=\"1.0.0\"\n");
+        assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+
+        let options = SerializerOptions {
+            unquoted_numeric_strings: true,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert_eq!(output, "PRICE=-1.50\nNAME=\"1.0.0\"\n");
+        assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn test_emit_checksum() {
+        use super::verify;
+
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("s"),
+        };
+        let options = SerializerOptions {
+            emit_checksum: true,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert!(output.starts_with("INT32=1\nSTRING=\"s\"\n# sha256="));
+        assert_eq!(verify(&output), Ok(()));
+
+        let tampered = output.replace("INT32=1", "INT32=2");
+        assert_eq!(verify(&tampered), Err(Error::ChecksumMismatch));
+
+        let truncated = "INT32=1\n";
+        assert_eq!(verify(truncated), Err(Error::MissingChecksum));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_naive_date() {
+        use chrono::NaiveDate;
+        use serde_derive::Deserialize;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            date: NaiveDate,
+        }
+
+        let test = Test {
+            date: NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+        };
+        for quoting in [Quoting::Double, Quoting::Safe] {
+            let options = SerializerOptions {
+                quoting,
+                ..SerializerOptions::default()
+            };
+            let output = to_string_with_options(&test, options).unwrap();
+            assert_eq!(output, "DATE=\"2024-01-02\"\n");
+            assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+        }
+
+        // Dates have no shell characters, they survive without quotes too.
+        let options = SerializerOptions {
+            quoting: Quoting::None,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert_eq!(output, "DATE=2024-01-02\n");
+        assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+    }
+
+    #[test]
+    fn test_to_string_filtered() {
+        #[derive(Serialize)]
+        struct Test {
+            db: Db,
+            cache: Db,
+            debug: bool,
+        }
+
+        #[derive(Serialize)]
+        struct Db {
+            host: String,
+            port: u16,
+        }
+
+        let test = Test {
+            db: Db {
+                host: String::from("db"),
+                port: 5432,
+            },
+            cache: Db {
+                host: String::from("cache"),
+                port: 6379,
+            },
+            debug: true,
+        };
+        let expected = "DB_HOST=\"db\"\nDB_PORT=5432\n";
+        assert_eq!(to_string_filtered(&test, &["DB_*"]).unwrap(), expected);
+
+        let expected = "CACHE_PORT=6379\nDEBUG=true\n";
+        assert_eq!(
+            to_string_filtered(&test, &["cache_port", "DEBUG"]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_field_order() {
+        #[derive(Serialize)]
+        struct Test {
+            first: i32,
+            second: Vec<i32>,
+            third: String,
+        }
+
+        let test = Test {
+            first: 1,
+            second: vec![2],
+            third: String::from("3"),
+        };
+        let options = SerializerOptions {
+            field_order: Some(vec![
+                String::from("third"),
+                String::from("FIRST"),
+            ]),
+            ..SerializerOptions::default()
+        };
+        let expected = "THIRD=\"3\"\nFIRST=1\nSECOND='2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_treat_pair_seq_as_map() {
+        #[derive(Serialize)]
+        struct Test {
+            conf: Vec<(&'static str, &'static str)>,
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            conf: vec![("host", "x"), ("port", "80")],
+            seq: vec![1, 2],
+        };
+        let options = SerializerOptions {
+            treat_pair_seq_as_map: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "CONF_HOST=\"x\"\nCONF_PORT=\"80\"\nSEQ='1,2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_key_filter() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            secret: String,
+            seq: Vec<i32>,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            int32: i32,
+        }
+
+        let test = Test {
+            int32: 1,
+            secret: String::from("s"),
+            seq: vec![1, 2],
+            nested: Nested { int32: 2 },
+        };
+        let options = SerializerOptions {
+            key_filter: Some(Box::new(|keys: &[String]| match keys {
+                [key] if key == "SECRET" => None,
+                [parent, key] if parent == "NESTED" => {
+                    Some(vec![String::from("RENAMED"), key.clone()])
+                }
+                _ => Some(keys.to_vec()),
+            })),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nSEQ='1,2'\nRENAMED_INT32=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Dropping a sequence drops all of its elements too
+        let options = SerializerOptions {
+            key_filter: Some(Box::new(|keys: &[String]| match keys {
+                [key] if key == "SEQ" => None,
+                _ => Some(keys.to_vec()),
+            })),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nSECRET=\"s\"\nNESTED_INT32=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    const FIELDS: [&str; 8] = ["a", "b", "c", "d", "e", "f", "g", "h"];
+
+    // `depth` levels of `nested` structs, wrapping a struct of `width` times
+    // each of `FIELDS`.
+    struct Deep {
+        depth: usize,
+        width: usize,
+    }
+
+    impl serde::Serialize for Deep {
+        fn serialize<S: serde::Serializer>(
+            &self,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeStruct;
+
+            let mut s = serializer.serialize_struct("Deep", 1)?;
+            if self.depth == 0 {
+                for i in 0..self.width {
+                    s.serialize_field(FIELDS[i % FIELDS.len()], &i)?;
+                }
+            } else {
+                let nested = Deep {
+                    depth: self.depth - 1,
+                    width: self.width,
+                };
+                s.serialize_field("nested", &nested)?;
+            }
+            s.end()
+        }
+    }
+
+    // `key` and `key_lens` must match `keys` joined from scratch.
+    fn check_key(serializer: &Serializer) {
+        assert_eq!(serializer.key, serializer.keys.join("_"));
+        assert_eq!(serializer.key_lens.len(), serializer.keys.len());
+        let mut len = 0;
+        for (i, segment) in serializer.keys.iter().enumerate() {
+            assert_eq!(serializer.key_lens[i], len);
+            if i > 0 {
+                len += 1;
+            }
+            len += segment.len();
+        }
+    }
+
+    #[test]
+    fn test_deep_wide_struct() {
+        let (depth, width) = (200, 500);
+        let prefix = vec!["NESTED"; depth].join("_");
+        let expected = (0..width)
+            .map(|i| {
+                let field = FIELDS[i % FIELDS.len()].to_uppercase();
+                format!("{}_{}={}\n", prefix, field, i)
+            })
+            .collect::<String>();
+        assert_eq!(to_string(&Deep { depth, width }).unwrap(), expected);
+
+        // The key is kept up to date as segments are pushed and popped
+        let mut serializer = Serializer::new(SerializerOptions::default());
+        for _ in 0..depth {
+            serializer.push_name("nested");
+            check_key(&serializer);
+        }
+        for i in 0..width {
+            serializer.push_name(FIELDS[i % FIELDS.len()]);
+            check_key(&serializer);
+            serializer.pop_key();
+            check_key(&serializer);
+        }
+        while !serializer.keys.is_empty() {
+            serializer.pop_key();
+            check_key(&serializer);
+        }
+        assert!(serializer.key.is_empty());
+
+        // and nothing is left once a value is serialized
+        let mut serializer = Serializer::new(SerializerOptions::default());
+        let deep = Deep { depth, width };
+        serde::Serialize::serialize(&deep, &mut serializer).unwrap();
+        check_key(&serializer);
+        assert!(serializer.key.is_empty());
+    }
+
+    #[test]
+    fn test_very_deep_struct() {
+        // Each level recurses into the serializer, the test thread's stack
+        // must still hold 500 of them.
+        let expected = format!("{}_A=0\n", vec!["NESTED"; 500].join("_"));
+        let deep = Deep {
+            depth: 500,
+            width: 1,
+        };
+        assert_eq!(to_string(&deep).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_wrap_width() {
+        #[derive(Serialize)]
+        struct Test {
+            string: String,
+        }
+
+        let test = Test {
+            string: "0123456789\\".repeat(5),
+        };
+        let options = SerializerOptions {
+            wrap_width: Some(20),
+            quoting: Quoting::Safe,
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test, options).unwrap();
+        assert_eq!(
+            output,
+            "STRING=\"0123456789\\\n\\\\0123456789\\\\01234\\\n\
+             56789\\\\0123456789\\\\\\\n0123456789\\\\\"\n"
+        );
+        assert!(output.lines().all(|line| line.chars().count() <= 20));
+
+        let unwrapped = SerializerOptions {
+            quoting: Quoting::Safe,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            output.replace("\\\n", ""),
+            to_string_with_options(&test, unwrapped).unwrap()
+        );
+
+        #[cfg(unix)]
+        {
+            let script = format!("{}printf '%s' \"$STRING\"", output);
+            let sourced = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(script)
+                .output()
+                .unwrap();
+            assert_eq!(sourced.stdout, test.string.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_float_format() {
+        #[derive(Serialize)]
+        struct Test {
+            float32: f32,
+            float64: f64,
+            large: f64,
+            small: f64,
+        }
+
+        let test = Test {
+            float32: 1.1,
+            float64: -150.25,
+            large: 1e21,
+            small: 0.0001,
+        };
+        let options = |float_format| SerializerOptions {
+            float_format,
+            ..SerializerOptions::default()
+        };
+        let expected = "FLOAT32=1.1\nFLOAT64=-150.25\n\
+                        LARGE=1000000000000000000000\nSMALL=0.0001\n";
+        assert_eq!(
+            to_string_with_options(&test, options(FloatFormat::Shortest))
+                .unwrap(),
+            expected
+        );
+
+        let expected = "FLOAT32=1.100\nFLOAT64=-150.250\n\
+                        LARGE=1000000000000000000000.000\nSMALL=0.000\n";
+        assert_eq!(
+            to_string_with_options(
+                &test,
+                options(FloatFormat::FixedDecimals(3))
+            )
+            .unwrap(),
+            expected
+        );
+
+        let expected = "FLOAT32=1.1e0\nFLOAT64=-1.5025e2\n\
+                        LARGE=1e21\nSMALL=1e-4\n";
+        assert_eq!(
+            to_string_with_options(&test, options(FloatFormat::Scientific))
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_float_precision() {
+        #[derive(Serialize)]
+        struct Test {
+            float32: f32,
+            float64: f64,
+        }
+
+        let test = Test {
+            float32: 1.1,
+            float64: 0.123456,
+        };
+        assert_eq!(
+            to_string(&test).unwrap(),
+            "FLOAT32=1.1\nFLOAT64=0.123456\n"
+        );
+
+        let options = |error_on_precision_loss| SerializerOptions {
+            float_precision: Some(2),
+            error_on_precision_loss,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options(false)).unwrap(),
+            "FLOAT32=1.10\nFLOAT64=0.12\n"
+        );
+        assert_eq!(
+            to_string_with_options(&test, options(true)).unwrap_err(),
+            Error::PrecisionLoss(String::from("FLOAT64"))
+        );
+
+        let test = Test {
+            float32: 1.1,
+            float64: 0.12,
+        };
+        assert_eq!(
+            to_string_with_options(&test, options(true)).unwrap(),
+            "FLOAT32=1.10\nFLOAT64=0.12\n"
+        );
+    }
+
+    #[test]
+    fn test_unit() {
+        use std::marker::PhantomData;
+
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            unit: (),
+            phantom: PhantomData<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            unit: (),
+            phantom: PhantomData,
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\n");
+
+        let options = SerializerOptions {
+            unit_as_empty_string: true,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap(),
+            "INT32=1\nUNIT=\"\"\nPHANTOM=\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_dotted_properties() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Test {
+            server: Server,
+            urls: BTreeMap<&'static str, &'static str>,
+        }
+
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Server {
+            maxThreads: u32,
+            motd: &'static str,
+            tags: Vec<&'static str>,
+        }
+
+        let test = Test {
+            server: Server {
+                maxThreads: 8,
+                motd: " C:\\app\nwelcome",
+                tags: vec!["a", "b"],
+            },
+            urls: vec![("http://x=y", "1")].into_iter().collect(),
+        };
+        let options = SerializerOptions {
+            format: Format::DottedProperties,
+            ..SerializerOptions::default()
+        };
+        let expected = "server.maxThreads=8\n\
+                        server.motd=\\ C:\\\\app\\nwelcome\n\
+                        server.tags=a,b\n\
+                        urls.http\\://x\\=y=1\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_docker_env_file() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: String,
+            seq: Vec<&'static str>,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("say \"$HOME\""),
+            seq: vec!["a", "b"],
+        };
+        let options = || SerializerOptions {
+            format: Format::DockerEnvFile,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options()).unwrap(),
+            "INT32=1\nSTRING=say \"$HOME\"\nSEQ=a,b\n"
+        );
+
+        let test = Test {
+            int32: 1,
+            string: String::from("first\nsecond"),
+            seq: vec![],
+        };
+        assert_eq!(
+            to_string_with_options(&test, options()).unwrap_err(),
+            Error::MultilineValue(String::from("STRING"))
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+        enum Key {
+            VariantA,
+            VariantB,
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            map: BTreeMap<Key, i32>,
+            strings: BTreeMap<&'static str, i32>,
+        }
+
+        let test = Test {
+            map: vec![(Key::VariantA, 1), (Key::VariantB, 2)]
+                .into_iter()
+                .collect(),
+            strings: vec![("host", 3)].into_iter().collect(),
+        };
+        let expected = "MAP_VARIANTA=1\nMAP_VARIANTB=2\nSTRINGS_HOST=3\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let map = vec![((1, 2), 1)].into_iter().collect::<BTreeMap<_, _>>();
+        assert_eq!(to_string(&map).unwrap_err(), Error::InvalidMapKey);
+    }
+
+    #[test]
+    fn test_case_style_preserve() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize)]
+        enum E {
+            Newtype(i32),
+        }
+
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Test {
+            dbHost: String,
+            map: HashMap<&'static str, i32>,
+            variant: E,
+        }
+
+        let test = Test {
+            dbHost: String::from("x"),
+            map: vec![("MixedCase", 1)].into_iter().collect(),
+            variant: E::Newtype(2),
+        };
+        let options = SerializerOptions {
+            case_style: CaseStyle::Preserve,
+            ..SerializerOptions::default()
+        };
+        let expected = "dbHost=\"x\"\nmap_MixedCase=1\nvariant_Newtype=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_raw_value() {
+        #[derive(Serialize)]
+        struct Test {
+            key: RawValue,
+            string: String,
+        }
+
+        let test = Test {
+            key: RawValue(String::from("$(date)")),
+            string: String::from("$(date)"),
+        };
+        let expected = "KEY=$(date)\nSTRING=\"$(date)\"\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_env_ref() {
+        #[derive(Serialize)]
+        struct Test {
+            key: EnvRef,
+            string: String,
+        }
+
+        let test = Test {
+            key: EnvRef(String::from("HOME")),
+            string: String::from("HOME"),
+        };
+        let expected = "KEY=${HOME}\nSTRING=\"HOME\"\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_bytes_encoding() {
+        struct Bytes(&'static [u8]);
+
+        impl serde::Serialize for Bytes {
+            fn serialize<S>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            bytes: Bytes,
+        }
+
+        let test = Test {
+            bytes: Bytes(b"hi"),
+        };
+        assert_eq!(to_string(&test).unwrap(), "BYTES='104,105'\n");
+
+        let options = SerializerOptions {
+            bytes_encoding: BytesEncoding::Utf8,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap(),
+            "BYTES=\"hi\"\n"
+        );
+
+        let test = Test {
+            bytes: Bytes(b"\xff\xfe"),
+        };
+        let options = SerializerOptions {
+            bytes_encoding: BytesEncoding::Utf8,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options),
+            Err(Error::InvalidUtf8(String::from("BYTES")))
+        );
+    }
+
+    #[test]
+    fn test_to_string_non_default() {
+        #[derive(Serialize, Default, PartialEq)]
+        struct Test {
+            int32: i32,
+            string: String,
+            seq: Vec<u32>,
+            option: Option<u32>,
+        }
+
+        let test = Test {
+            string: String::from("changed"),
+            ..Test::default()
+        };
+        let expected = "STRING=\"changed\"\n";
+        assert_eq!(to_string_non_default(&test).unwrap(), expected);
+
+        assert_eq!(to_string_non_default(&Test::default()).unwrap(), "");
+
+        // Variables absent from the default are always written.
+        let test = Test {
+            option: Some(1),
+            ..Test::default()
+        };
+        assert_eq!(to_string_non_default(&test).unwrap(), "OPTION=1\n");
+    }
+
+    #[test]
+    fn test_to_string_checked() {
+        #[derive(Serialize)]
+        struct Test {
+            host: String,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            port: u16,
+            extra: bool,
+        }
+
+        let test = Test {
+            host: String::from("localhost"),
+            nested: Nested {
+                port: 80,
+                extra: true,
+            },
+        };
+        let mut allowed: HashSet<String> = ["HOST", "NESTED_PORT"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        assert_eq!(
+            to_string_checked(&test, &allowed).unwrap_err(),
+            Error::KeyNotAllowed(String::from("NESTED_EXTRA"))
+        );
+
+        allowed.insert(String::from("NESTED_EXTRA"));
+        assert_eq!(
+            to_string_checked(&test, &allowed).unwrap(),
+            to_string(&test).unwrap()
+        );
+
+        // The other options apply, names are checked as written
+        let options = SerializerOptions {
+            prefix: Some(String::from("APP")),
+            allowed_keys: Some(allowed.clone()),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap_err(),
+            Error::KeyNotAllowed(String::from("APP_HOST"))
+        );
+
+        // Inside blocks, the names of the inner document are checked
+        let allowed: HashSet<String> = ["HOST", "NESTED", "PORT"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        let options = SerializerOptions {
+            nested_blocks: true,
+            allowed_keys: Some(allowed),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap_err(),
+            Error::KeyNotAllowed(String::from("EXTRA"))
+        );
+    }
+
+    #[test]
+    fn test_to_string_many() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: &'static str,
+        }
+
+        let tests = [
+            Test {
+                int32: 1,
+                string: "a",
+            },
+            Test {
+                int32: 2,
+                string: "b",
+            },
+        ];
+        let expected = "INT32=1\nSTRING=\"a\"\n\n---\nINT32=2\nSTRING=\"b\"\n";
+        assert_eq!(to_string_many(&tests, "\n---\n").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_prefix_reserved_keywords() {
+        #[derive(Serialize)]
+        struct Test {
+            r#do: i32,
+            done_at: i32,
+            r#in: Vec<u32>,
+        }
+
+        let test = Test {
+            r#do: 1,
+            done_at: 2,
+            r#in: vec![3],
+        };
+        assert_eq!(to_string(&test).unwrap(), "DO=1\nDONE_AT=2\nIN='3'\n");
+
+        let options = SerializerOptions {
+            prefix_reserved_keywords: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "VAR_DO=1\nDONE_AT=2\nVAR_IN='3'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        let options = SerializerOptions {
+            prefix_reserved_keywords: true,
+            reserved_keyword_prefix: Some(String::from("SAFE_")),
+            ..SerializerOptions::default()
+        };
+        let expected = "SAFE_DO=1\nDONE_AT=2\nSAFE_IN='3'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_nonfinite() {
+        #[derive(Serialize)]
+        struct Test {
+            nan: f64,
+            inf: f32,
+            neg_inf: f64,
+            int32: i32,
+        }
+
+        let test = Test {
+            nan: f64::NAN,
+            inf: f32::INFINITY,
+            neg_inf: f64::NEG_INFINITY,
+            int32: 1,
+        };
+        assert_eq!(
+            to_string(&test),
+            Err(Error::NonFiniteFloat(String::from("NAN")))
+        );
+
+        let options = SerializerOptions {
+            nonfinite: NonFinitePolicy::AsString,
+            ..SerializerOptions::default()
+        };
+        let expected = "NAN=\"NaN\"\nINF=\"inf\"\nNEG_INF=\"-inf\"\nINT32=1\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        let options = SerializerOptions {
+            nonfinite: NonFinitePolicy::AsNull,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap(),
+            "INT32=1\n"
+        );
+    }
+
+    #[test]
+    fn test_key_joiner() {
+        #[derive(Serialize)]
+        struct Test {
+            a: A,
+        }
+
+        #[derive(Serialize)]
+        struct A {
+            b: B,
+        }
+
+        #[derive(Serialize)]
+        struct B {
+            c: i32,
+        }
+
+        let test = Test {
+            a: A { b: B { c: 1 } },
+        };
+        let options = SerializerOptions {
+            key_joiner: Some(|depth| if depth == 1 { "__" } else { "_" }),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap(),
+            "A__B_C=1\n"
+        );
+
+        let options = SerializerOptions {
+            key_joiner: Some(|depth| if depth == 1 { "__" } else { "_" }),
+            strip_prefix: Some(String::from("A")),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(to_string_with_options(&test, options).unwrap(), "B_C=1\n");
+    }
+
+    #[test]
+    fn test_borrowed_seq_elements() {
+        use std::borrow::Cow;
+
+        #[derive(Serialize)]
+        struct Owned {
+            seq: Vec<String>,
+        }
+
+        #[derive(Serialize)]
+        struct Borrowed<'a> {
+            seq: &'a [&'a str],
+        }
+
+        #[derive(Serialize)]
+        struct Cows<'a> {
+            seq: Vec<Cow<'a, str>>,
+        }
+
+        let elements = ["a b", "c,d", "e"];
+        let owned = Owned {
+            seq: elements.iter().map(|e| e.to_string()).collect(),
+        };
+        let borrowed = Borrowed { seq: &elements };
+        let cows = Cows {
+            seq: vec![
+                Cow::Borrowed(elements[0]),
+                Cow::Owned(elements[1].to_string()),
+                Cow::Borrowed(elements[2]),
+            ],
+        };
+        let expected = "SEQ='\"a b\",\"c,d\",\"e\"'\n";
+        assert_eq!(to_string(&owned).unwrap(), expected);
+        assert_eq!(to_string(&borrowed).unwrap(), expected);
+        assert_eq!(to_string(&cows).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_respect_serde_rename_all() {
+        #[derive(Serialize)]
+        #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+        struct Test {
+            db_host: &'static str,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Nested {
+            max_size: u32,
+            #[serde(rename = "Port")]
+            port: u16,
+        }
+
+        let test = Test {
+            db_host: "localhost",
+            nested: Nested {
+                max_size: 1,
+                port: 2,
+            },
+        };
+        let expected =
+            "DB_HOST=\"localhost\"\nNESTED_MAXSIZE=1\nNESTED_PORT=2\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let options = SerializerOptions {
+            respect_serde_rename_all: true,
+            ..SerializerOptions::default()
+        };
+        let expected =
+            "DB_HOST=\"localhost\"\nNESTED_maxSize=1\nNESTED_Port=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_chars_as_string() {
+        #[derive(Serialize)]
+        struct Test {
+            key: Vec<char>,
+            mixed: (char, u32),
+        }
+
+        let test = Test {
+            key: vec!['a', 'b', 'c'],
+            mixed: ('d', 1),
+        };
+        let expected = "KEY='\"a\",\"b\",\"c\"'\nMIXED='\"d\",1'\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let options = SerializerOptions {
+            chars_as_string: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "KEY=\"abc\"\nMIXED='\"d\",1'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_line_suffix() {
+        #[derive(Serialize)]
+        struct Test {
+            foo: i32,
+            bar: i32,
+            seq: Vec<u32>,
+        }
+
+        let test = Test {
+            foo: 1,
+            bar: 2,
+            seq: vec![3, 4],
+        };
+        let options = SerializerOptions {
+            line_suffix: Some(String::from(";")),
+            ..SerializerOptions::default()
+        };
+        let expected = "FOO=1;\nBAR=2;\nSEQ='3,4';\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_case_collision() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Test {
+            fooBar: i32,
+            foobar: i32,
+        }
+
+        let test = Test {
+            fooBar: 1,
+            foobar: 2,
+        };
+        assert_eq!(
+            to_string(&test),
+            Err(Error::CaseCollision(String::from("FOOBAR")))
+        );
+
+        let map: BTreeMap<_, _> =
+            vec![("a", 1), ("b", 2), ("A", 3)].into_iter().collect();
+        assert_eq!(
+            to_string(&map),
+            Err(Error::CaseCollision(String::from("A")))
+        );
+
+        // Nothing is cased, so nothing collides.
+        let options = SerializerOptions {
+            case_style: CaseStyle::Preserve,
+            ..SerializerOptions::default()
+        };
+        let expected = "fooBar=1\nfoobar=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Names are joined like the keys
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Joined {
+            a: Inner,
+            a__b: i32,
+        }
+
+        #[derive(Serialize)]
+        struct Inner {
+            #[serde(rename = "B")]
+            b: i32,
+        }
+
+        let joined = Joined {
+            a: Inner { b: 1 },
+            a__b: 2,
+        };
+        let options = SerializerOptions {
+            key_joiner: Some(|_| "__"),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&joined, options),
+            Err(Error::CaseCollision(String::from("A__B")))
+        );
+    }
+
+    #[test]
+    fn test_case_style_bool_values() {
+        #[derive(Serialize)]
+        struct Test {
+            enabled: bool,
+            disabled: bool,
+            flags: Vec<bool>,
+        }
+
+        let test = Test {
+            enabled: true,
+            disabled: false,
+            flags: vec![true, false],
+        };
+        // The case style only applies to the keys.
+        let options = SerializerOptions {
+            case_style: CaseStyle::Upper,
+            ..SerializerOptions::default()
+        };
+        let expected = "ENABLED=true\nDISABLED=false\nFLAGS='true,false'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        let options = SerializerOptions {
+            case_style: CaseStyle::Preserve,
+            ..SerializerOptions::default()
+        };
+        let expected = "enabled=true\ndisabled=false\nflags='true,false'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_top_level_map() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize)]
+        struct ServiceConfig {
+            host: &'static str,
+            port: u16,
+        }
+
+        let mut services = HashMap::new();
+        services.insert(
+            "web",
+            ServiceConfig {
+                host: "w",
+                port: 80,
+            },
+        );
+        let expected = "WEB_HOST=\"w\"\nWEB_PORT=80\n";
+        assert_eq!(to_string(&services).unwrap(), expected);
+
+        services.insert(
+            "db",
+            ServiceConfig {
+                host: "d",
+                port: 5432,
+            },
+        );
+        let output = to_string(&services).unwrap();
+        let mut lines: Vec<_> = output.lines().collect();
+        lines.sort_unstable();
+        let expected = vec![
+            "DB_HOST=\"d\"",
+            "DB_PORT=5432",
+            "WEB_HOST=\"w\"",
+            "WEB_PORT=80",
+        ];
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_map_format() {
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Test {
+            db: BTreeMap<&'static str, i32>,
+        }
+
+        let test = Test {
+            db: vec![("host", 1), ("port", 2)].into_iter().collect(),
+        };
+        let options = SerializerOptions {
+            map_format: MapFormat::KeyedNested,
+            ..SerializerOptions::default()
+        };
+        let expected = "DB_HOST=1\nDB_PORT=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Identifier keys stay flat
+        let options = SerializerOptions {
+            map_format: MapFormat::IndexedEntries,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Only the other ones are indexed
+        let test = Test {
+            db: vec![("1st", 1), ("host", 2), ("x-forwarded-for", 3)]
+                .into_iter()
+                .collect(),
+        };
+        let options = SerializerOptions {
+            map_format: MapFormat::IndexedEntries,
+            ..SerializerOptions::default()
+        };
+        let expected = "DB_0_KEY=\"1st\"\nDB_0_VALUE=1\n\
+                        DB_HOST=2\n\
+                        DB_1_KEY=\"x-forwarded-for\"\nDB_1_VALUE=3\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Any key can be written as a value
+        let map = vec![((1, 2), 1)].into_iter().collect::<BTreeMap<_, _>>();
+        let options = SerializerOptions {
+            map_format: MapFormat::IndexedEntries,
+            ..SerializerOptions::default()
+        };
+        let expected = "0_KEY='1,2'\n0_VALUE=1\n";
+        assert_eq!(to_string_with_options(&map, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_assignment() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            seq: vec![1, 2],
+        };
+        let options = SerializerOptions {
+            assignment: Some(String::from("=")),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nSEQ='1,2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        let options = SerializerOptions {
+            assignment: Some(String::from(" = ")),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32 = 1\nSEQ = '1,2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_enum_as_index() {
+        #[derive(Serialize)]
+        enum Status {
+            Starting,
+            Running,
+            Stopped,
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            status: Status,
+            statuses: Vec<Status>,
+        }
+
+        let test = Test {
+            status: Status::Stopped,
+            statuses: vec![Status::Starting, Status::Running],
+        };
+        let options = SerializerOptions {
+            enum_as_index: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "STATUS=2\nSTATUSES='0,1'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_single_line() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("with spaces"),
+        };
+        let options = SerializerOptions {
+            single_line: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1 STRING=\"with spaces\"";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        #[derive(Serialize)]
+        struct Test {
+            name: String,
+            database: Database,
+            port: u16,
+        }
+
+        #[derive(Serialize)]
+        struct Database {
+            host: String,
+            pool: Pool,
+            tags: Vec<String>,
+        }
+
+        #[derive(Serialize)]
+        struct Pool {
+            size: u32,
+        }
+
+        let test = Test {
+            name: String::from("app"),
+            database: Database {
+                host: String::from("localhost"),
+                pool: Pool { size: 4 },
+                tags: vec![String::from("a")],
+            },
+            port: 80,
+        };
+        let options = SerializerOptions {
+            nested_blocks: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "NAME=\"app\"\n\
+            DATABASE=$'HOST=\"localhost\"\\nPOOL=$\\'SIZE=4\\'\\nTAGS=\\'\"a\"\\''\n\
+            PORT=80\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // The prefix isn't a block of its own
+        let options = SerializerOptions {
+            nested_blocks: true,
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        let expected = "APP_NAME=\"app\"\n\
+            APP_DATABASE=$'HOST=\"localhost\"\\nPOOL=$\\'SIZE=4\\'\\nTAGS=\\'\"a\"\\''\n\
+            APP_PORT=80\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Everything written inside the blocks is traced and validated
+        let options = SerializerOptions {
+            nested_blocks: true,
+            debug_trace: true,
+            ..SerializerOptions::default()
+        };
+        let mut trace = Vec::new();
+        to_string_with_trace(&test.database, options, &mut trace).unwrap();
+        let expected = "[\"HOST\"] = \"localhost\"\n\
+                        [\"SIZE\"] = 4\n\
+                        [\"POOL\"] = $'SIZE=4'\n\
+                        [\"TAGS\"] = \"a\"\n";
+        assert_eq!(String::from_utf8(trace).unwrap(), expected);
+
+        let options = SerializerOptions {
+            nested_blocks: true,
+            validate: true,
+            key_filter: Some(Box::new(|_| Some(vec![String::from("SAME")]))),
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test.database, options).unwrap_err(),
+            Error::DuplicateKey(String::from("SAME"))
+        );
+    }
+
+    #[test]
+    fn test_nested_blocks_tracked() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Serialize)]
+        struct Test {
+            name: String,
+            database: Tracked<Rc<Database>>,
+        }
+
+        #[derive(Serialize)]
+        struct Database {
+            host: String,
+        }
+
+        let test = Tracked(Rc::new(Test {
+            name: String::from("app"),
+            database: Tracked(Rc::new(Database {
+                host: String::from("localhost"),
+            })),
+        }));
+        // `Tracked` pointers are written as the value they point to
+        let options = SerializerOptions {
+            nested_blocks: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "NAME=\"app\"\nDATABASE=$'HOST=\"localhost\"'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+        let options = SerializerOptions {
+            section_headers: true,
+            ..SerializerOptions::default()
+        };
+        let expected =
+            "NAME=\"app\"\n# [database]\nDATABASE_HOST=\"localhost\"\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Cycles are found across blocks
+        #[derive(Serialize)]
+        struct Node {
+            name: String,
+            next: Option<Tracked<Rc<RefCell<Node>>>>,
+        }
+
+        let a = Rc::new(RefCell::new(Node {
+            name: String::from("a"),
+            next: None,
+        }));
+        let b = Rc::new(RefCell::new(Node {
+            name: String::from("b"),
+            next: Some(Tracked(a.clone())),
+        }));
+        a.borrow_mut().next = Some(Tracked(b.clone()));
+        let options = SerializerOptions {
+            nested_blocks: true,
+            detect_cycles: true,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&Tracked(a.clone()), options).unwrap_err(),
+            Error::CycleDetected(String::from("NEXT"))
+        );
+        // Break the cycle so that the nodes are dropped.
+        b.borrow_mut().next = None;
+    }
+
+    #[test]
+    fn test_section_headers() {
+        #[derive(Serialize)]
+        struct Test {
+            name: String,
+            database: Database,
+            cache: Cache,
+        }
+
+        #[derive(Serialize)]
+        struct Database {
+            host: String,
+            pool: Pool,
+        }
+
+        #[derive(Serialize)]
+        struct Pool {
+            size: u32,
+        }
+
+        #[derive(Serialize)]
+        struct Cache {
+            ttl: u32,
+        }
+
+        let test = Test {
+            name: String::from("app"),
+            database: Database {
+                host: String::from("localhost"),
+                pool: Pool { size: 4 },
+            },
+            cache: Cache { ttl: 60 },
+        };
+        let options = SerializerOptions {
+            section_headers: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "NAME=\"app\"\n\
+            # [database]\n\
+            DATABASE_HOST=\"localhost\"\n\
+            DATABASE_POOL_SIZE=4\n\
+            # [cache]\n\
+            CACHE_TTL=60\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_annotate_types() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            uint8: u8,
+            float64: f64,
+            boolean: bool,
+            string: String,
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            uint8: 1,
+            float64: 1.5,
+            boolean: true,
+            string: String::from("s"),
+            seq: vec![1],
+        };
+        let options = SerializerOptions {
+            annotate_types: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "# int32\nINT32=1\n\
+                        # uint8\nUINT8=1\n\
+                        # float64\nFLOAT64=1.5\n\
+                        # bool\nBOOLEAN=true\n\
+                        # string\nSTRING=\"s\"\n\
+                        # seq\nSEQ='1'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_track_path() {
+        #[derive(Serialize)]
+        struct Test {
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            servers: Vec<Server>,
+        }
+
+        #[derive(Serialize)]
+        struct Server {
+            host: String,
+            weight: f64,
+        }
+
+        let server = |weight| Server {
+            host: String::from("localhost"),
+            weight,
+        };
+        let test = Test {
+            nested: Nested {
+                servers: vec![server(1.0), server(f64::NAN)],
+            },
+        };
+        let options = SerializerOptions {
+            track_path: true,
+            ..SerializerOptions::default()
+        };
+        let err = to_string_with_options(&test, options).unwrap_err();
+        assert_eq!(err.path(), "nested.servers.1.weight");
+        let inner =
+            Error::NonFiniteFloat(String::from("NESTED_SERVERS_WEIGHT"));
+        assert_eq!(
+            err,
+            Error::Path(
+                String::from("nested.servers.1.weight"),
+                Box::new(inner)
+            )
+        );
+
+        // Without `track_path`, errors are left as they are.
+        let err = to_string(&test).unwrap_err();
+        assert_eq!(err.path(), "");
+    }
+
+    #[test]
+    fn test_detect_cycles() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Serialize)]
+        struct Node {
+            name: String,
+            next: Option<Tracked<Rc<RefCell<Node>>>>,
+        }
+
+        #[derive(Serialize)]
+        struct Both {
+            first: Tracked<Rc<RefCell<Node>>>,
+            second: Tracked<Rc<RefCell<Node>>>,
+        }
+
+        let node = |name: &str| {
+            Rc::new(RefCell::new(Node {
+                name: name.to_string(),
+                next: None,
+            }))
+        };
+        let options = || SerializerOptions {
+            detect_cycles: true,
+            ..SerializerOptions::default()
+        };
+
+        // The same node in two places is not a cycle.
+        let (a, b) = (node("a"), node("b"));
+        a.borrow_mut().next = Some(Tracked(b.clone()));
+        let both = Both {
+            first: Tracked(a.clone()),
+            second: Tracked(b.clone()),
+        };
+        let expected =
+            "FIRST_NAME=\"a\"\nFIRST_NEXT_NAME=\"b\"\nSECOND_NAME=\"b\"\n";
+        assert_eq!(to_string_with_options(&both, options()).unwrap(), expected);
+
+        b.borrow_mut().next = Some(Tracked(a.clone()));
+        assert_eq!(
+            to_string_with_options(&Tracked(a.clone()), options()).unwrap_err(),
+            Error::CycleDetected(String::from("NEXT_NEXT"))
+        );
+        // Break the cycle so that the nodes are dropped.
+        b.borrow_mut().next = None;
+    }
+
+    #[test]
+    fn test_debug_trace() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            seq: Vec<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            nested: Nested { seq: vec![2, 3] },
+        };
+        let options = SerializerOptions {
+            debug_trace: true,
+            ..SerializerOptions::default()
+        };
+        let mut trace = Vec::new();
+        let output = to_string_with_trace(&test, options, &mut trace).unwrap();
+        assert_eq!(output, to_string(&test).unwrap());
+        let expected = "[\"INT32\"] = 1\n\
+                        [\"NESTED\", \"SEQ\"] = 2\n\
+                        [\"NESTED\", \"SEQ\"] = 3\n";
+        assert_eq!(String::from_utf8(trace).unwrap(), expected);
+
+        // Nothing is traced unless asked for
+        let mut trace = Vec::new();
+        to_string_with_trace(&test, SerializerOptions::default(), &mut trace)
+            .unwrap();
+        assert!(trace.is_empty());
+
+        // Errors writing the trace are returned as soon as they happen
+        struct Failing(usize);
+
+        impl io::Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::Error::other("full"));
+                }
+                self.0 -= 1;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let options = SerializerOptions {
+            debug_trace: true,
+            ..SerializerOptions::default()
+        };
+        let result = to_string_with_trace(&test, options, &mut Failing(1));
+        assert_eq!(result, Err(Error::Io("full".to_string())));
+    }
+
+    #[test]
+    fn test_nested_struct() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            nested: Nested,
+            other_int32: i32,
+        }
+        #[derive(Serialize)]
+        struct Nested {
+            nested_again: NestedAgain,
+        }
+
+        #[derive(Serialize)]
+        struct NestedAgain {
+            int32: i32,
+        }
+
+        let test = Test {
+            int32: 1,
+            nested: Nested {
+                nested_again: NestedAgain { int32: 1 },
+            },
+            other_int32: 1,
+        };
+        let expected = "INT32=1\nNESTED_NESTED_AGAIN_INT32=1\nOTHER_INT32=1\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_expected_keys() {
+        #[derive(Serialize, Default)]
+        struct Test {
+            int32: i32,
+            option: Option<String>,
+            seq: Vec<i32>,
+            nested: Nested,
+        }
+
+        #[derive(Serialize, Default)]
+        struct Nested {
+            int32: i32,
+        }
+
+        let expected = vec!["INT32", "OPTION", "SEQ", "NESTED_INT32"];
+        assert_eq!(expected_keys::<Test>().unwrap(), expected);
+
+        // Only the keys actually written for this value
+        let expected = vec!["INT32", "SEQ", "NESTED_INT32"];
+        assert_eq!(keys_of(&Test::default()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_fold_single_field_structs() {
+        #[derive(Serialize)]
+        struct Test {
+            a: A,
+            single: Single,
+        }
+
+        #[derive(Serialize)]
+        struct A {
+            b: B,
+        }
+
+        #[derive(Serialize)]
+        struct B {
+            c: i32,
+        }
+
+        #[derive(Serialize)]
+        struct Single {
+            value: i32,
+        }
+
+        let test = Test {
+            a: A { b: B { c: 1 } },
+            single: Single { value: 2 },
+        };
+        let expected = "A_B_C=1\nSINGLE_VALUE=2\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let options = SerializerOptions {
+            fold_single_field_structs: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "A=1\nSINGLE=2\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        // Only nested structs are folded, not the one under the prefix
+        let options = SerializerOptions {
+            fold_single_field_structs: true,
+            prefix: Some(String::from("APP")),
+            ..SerializerOptions::default()
+        };
+        let output = to_string_with_options(&test.single, options).unwrap();
+        assert_eq!(output, "APP_VALUE=2\n");
+    }
+
+    #[test]
+    fn test_option() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            option_int32: Option<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            option_int32: Some(1),
+        };
+        let expected = "INT32=1\nOPTION_INT32=1\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+
+        let test = Test {
+            int32: 1,
+            option_int32: None,
+        };
+        let expected = "INT32=1\n";
+        assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_skip_false_bools() {
+        #[derive(Serialize)]
+        struct Test {
+            enabled: bool,
+            disabled: bool,
+            seq: Vec<bool>,
+        }
+
+        let test = Test {
+            enabled: true,
+            disabled: false,
+            seq: vec![true, false],
+        };
+        let options = SerializerOptions {
+            skip_false_bools: true,
+            ..SerializerOptions::default()
+        };
+        let expected = "ENABLED=true\nSEQ='true,false'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_nested_option() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            option: Option<Option<i32>>,
+        }
+
+        let test = Test {
+            int32: 1,
+            option: Some(Some(2)),
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\nOPTION=2\n");
+
+        let test = Test {
+            int32: 1,
+            option: Some(None),
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\n");
+
+        let test = Test {
+            int32: 1,
+            option: None,
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\n");
+
+        // Only the outer option has a presence flag
+        let options = || SerializerOptions {
+            presence_flag_suffix: Some(String::from("set")),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nOPTION_SET=false\n";
+        assert_eq!(to_string_with_options(&test, options()).unwrap(), expected);
+        let test = Test {
+            int32: 1,
+            option: Some(None),
+        };
+        let expected = "INT32=1\nOPTION_SET=true\n";
+        assert_eq!(to_string_with_options(&test, options()).unwrap(), expected);
+        let test = Test {
+            int32: 1,
+            option: Some(Some(2)),
+        };
+        let expected = "INT32=1\nOPTION=2\nOPTION_SET=true\n";
+        assert_eq!(to_string_with_options(&test, options()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_flag_style() {
+        #[derive(Serialize)]
+        struct Test {
+            verbose: bool,
+            quiet: bool,
+        }
+
+        let test = Test {
+            verbose: true,
+            quiet: false,
+        };
+        let options = SerializerOptions {
+            flag_style: FlagStyle::OneZero,
+            ..SerializerOptions::default()
+        };
+        let expected = "VERBOSE=1\nQUIET=0\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+
+        let options = SerializerOptions {
+            flag_style: FlagStyle::Presence,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&test, options).unwrap(),
+            "VERBOSE=1\n"
+        );
+    }
+
+    #[test]
+    fn test_none_placeholder() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            option_int32: Option<i32>,
+        }
+
+        let test = Test {
+            int32: 1,
+            option_int32: None,
+        };
+        let options = SerializerOptions {
+            none_placeholder: Some(String::from("__UNSET__")),
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nOPTION_INT32=\"__UNSET__\"\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_presence_flag_suffix() {
+        #[derive(Serialize)]
+        struct Test {
+            some: Option<String>,
+            none: Option<String>,
+            seq: Vec<Option<i32>>,
+        }
+
+        let test = Test {
+            some: Some(String::new()),
+            none: None,
+            seq: vec![Some(1), None],
+        };
+        let options = SerializerOptions {
+            presence_flag_suffix: Some(String::from("set")),
+            ..SerializerOptions::default()
+        };
+        let expected = "SOME=\"\"\nSOME_SET=true\nNONE_SET=false\nSEQ='1,'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_option_map() {
+        use std::collections::HashMap;
+
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            map: Option<HashMap<String, i32>>,
+        }
+
+        let test = Test {
+            int32: 1,
+            map: None,
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\n");
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        self.keys.push(key.to_uppercase());
-        // self.output += &(self.keys.join("_") +  + "=");
-        value.serialize(&mut **self)?;
-        self.keys.pop();
-        Ok(())
-    }
+        // An empty map has no entry to write, and doesn't leave an empty line
+        let test = Test {
+            int32: 1,
+            map: Some(HashMap::new()),
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\n");
 
-    fn end(self) -> Result<()> {
-        Ok(())
+        let test = Test {
+            int32: 1,
+            map: Some(vec![(String::from("key"), 2)].into_iter().collect()),
+        };
+        assert_eq!(to_string(&test).unwrap(), "INT32=1\nMAP_KEY=2\n");
     }
-}
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
-    type Ok = ();
-    type Error = Error;
+    #[test]
+    fn test_validate() {
+        use std::collections::BTreeMap;
 
-    fn serialize_field<T>(
-        &mut self,
-        _key: &'static str,
-        value: &T,
-    ) -> Result<()>
-    where
-        T: ?Sized + Serialize,
-    {
-        if !self.output.ends_with('{') {
-            self.output += ",";
+        #[derive(Serialize)]
+        struct Test {
+            a_b: i32,
+            a: A,
         }
-        // self.key.serialize(&mut **self)?;
-        self.output += ":";
-        value.serialize(&mut **self)
-    }
 
-    fn end(self) -> Result<()> {
-        self.output += "}}";
-        Ok(())
-    }
-}
+        #[derive(Serialize)]
+        struct A {
+            b: i32,
+        }
 
-////////////////////////////////////////////////////////////////////////////////
+        let test = Test {
+            a_b: 1,
+            a: A { b: 2 },
+        };
+        assert_eq!(to_string(&test).unwrap(), "A_B=1\nA_B=2\n");
 
-#[cfg(test)]
-mod tests {
-    use super::to_string;
-    use serde_derive::Serialize;
+        let options = SerializerOptions {
+            validate: true,
+            ..SerializerOptions::default()
+        };
+        let mut output = Vec::new();
+        assert_eq!(
+            to_writer_with_options(&mut output, &test, options).unwrap_err(),
+            Error::DuplicateKey(String::from("A_B"))
+        );
+        assert!(output.is_empty());
+
+        let map: BTreeMap<_, _> = vec![("1st", 1)].into_iter().collect();
+        let options = SerializerOptions {
+            validate: true,
+            ..SerializerOptions::default()
+        };
+        assert_eq!(
+            to_string_with_options(&map, options).unwrap_err(),
+            Error::InvalidKey(String::from("1ST"))
+        );
+    }
 
     #[test]
-    fn test_struct() {
+    fn test_to_string_and_map() {
         #[derive(Serialize)]
         struct Test {
-            uint8: u8,
-            int8: i8,
-            uint16: u16,
-            int16: i16,
-            uint32: u32,
             int32: i32,
-            uint64: u64,
-            int64: i64,
-            float32: f32,
-            float64: f64,
-            character: char,
             string: String,
+            seq: Vec<&'static str>,
+            option: Option<u8>,
+            nested: Nested,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            chars: Vec<char>,
+            flag: bool,
+            raw: RawValue,
         }
 
         let test = Test {
-            uint8: 1,
-            int8: 1,
-            uint16: 1,
-            int16: 1,
-            uint32: 1,
             int32: 1,
-            uint64: 1,
-            int64: 1,
-            float32: 1.0,
-            float64: 1.0,
-            character: 'c',
-            string: String::from("s"),
+            string: String::from("with \"quotes\""),
+            seq: vec!["b", "a c"],
+            option: None,
+            nested: Nested {
+                chars: vec!['x', 'y'],
+                flag: true,
+                raw: RawValue(String::from("$(date)")),
+            },
         };
-        let expected = "UINT8=1\nINT8=1\nUINT16=1\nINT16=1\nUINT32=1\nINT32=1\nUINT64=1\nINT64=1\nFLOAT32=1\nFLOAT64=1\nCHARACTER=\"c\"\nSTRING=\"s\"\n";
-        assert_eq!(to_string(&test).unwrap(), expected);
+        let (output, map) = to_string_and_map(&test).unwrap();
+        assert_eq!(output, to_string(&test).unwrap());
+        assert_eq!(map, to_map(&test).unwrap());
+        assert_eq!(map["SEQ"], "b,a c");
     }
 
     #[test]
-    fn test_seq() {
+    fn test_empty_seq() {
         #[derive(Serialize)]
         struct Test {
-            seq: Vec<&'static str>,
+            int32: i32,
+            seq: Vec<i32>,
         }
+
         let test = Test {
-            seq: vec!["a", "b"],
+            int32: 1,
+            seq: Vec::new(),
         };
-        let expected = "SEQ='\"a\",\"b\"'\n";
-        assert_eq!(to_string(&test).unwrap(), expected);
+        let output = |empty_seq| {
+            let options = SerializerOptions {
+                empty_seq,
+                ..SerializerOptions::default()
+            };
+            to_string_with_options(&test, options).unwrap()
+        };
+        assert_eq!(output(EmptySeqPolicy::EmptyQuotes), "INT32=1\nSEQ=''\n");
+        assert_eq!(output(EmptySeqPolicy::Omit), "INT32=1\n");
+        assert_eq!(output(EmptySeqPolicy::EmptyParens), "INT32=1\nSEQ=()\n");
 
-        // When we have a simple seq, we can't name the key properly
-        let test = vec!["a", "b"];
-        let expected = "='\"a\",\"b\"'\n";
-        assert_eq!(to_string(&test).unwrap(), expected);
+        // Sequences with elements are left alone.
+        let test = Test {
+            int32: 1,
+            seq: vec![2],
+        };
+        let options = SerializerOptions {
+            empty_seq: EmptySeqPolicy::EmptyParens,
+            ..SerializerOptions::default()
+        };
+        let expected = "INT32=1\nSEQ='2'\n";
+        assert_eq!(to_string_with_options(&test, options).unwrap(), expected);
     }
 
     #[test]
-    fn test_nested_struct() {
+    fn test_to_map() {
         #[derive(Serialize)]
         struct Test {
             int32: i32,
+            string: String,
+            seq: Vec<&'static str>,
             nested: Nested,
-            other_int32: i32,
         }
+
         #[derive(Serialize)]
         struct Nested {
-            nested_again: NestedAgain,
+            multiline: &'static str,
         }
 
+        let test = Test {
+            int32: 1,
+            string: String::from("with \"quotes\""),
+            seq: vec!["a", "b"],
+            nested: Nested { multiline: "a\nb" },
+        };
+        let map = to_map(&test).unwrap();
+        let expected: BTreeMap<String, String> = vec![
+            ("INT32", "1"),
+            ("STRING", "with \"quotes\""),
+            ("SEQ", "a,b"),
+            ("NESTED_MULTILINE", "a\nb"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(map, expected);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_to_nested_map() {
+        use super::to_nested_map;
+        use serde_json::json;
+
         #[derive(Serialize)]
-        struct NestedAgain {
+        struct Test {
             int32: i32,
+            seq: Vec<&'static str>,
+            nested: Nested,
+            nested_again: i32,
+        }
+
+        #[derive(Serialize)]
+        struct Nested {
+            string: &'static str,
+            inner: Inner,
+        }
+
+        #[derive(Serialize)]
+        struct Inner {
+            boolean: bool,
         }
 
         let test = Test {
             int32: 1,
+            seq: vec!["a", "b"],
             nested: Nested {
-                nested_again: NestedAgain { int32: 1 },
+                string: "text",
+                inner: Inner { boolean: true },
             },
-            other_int32: 1,
+            nested_again: 2,
         };
-        let expected = "INT32=1\nNESTED_NESTED_AGAIN_INT32=1\nOTHER_INT32=1\n";
-        assert_eq!(to_string(&test).unwrap(), expected);
+        let expected = json!({
+            "INT32": "1",
+            "SEQ": "a,b",
+            "NESTED": {
+                "STRING": "text",
+                "INNER": { "BOOLEAN": "true" },
+                "AGAIN": "2",
+            },
+        });
+        assert_eq!(to_nested_map(&test).unwrap(), expected);
+
+        #[derive(Serialize)]
+        struct Prefix {
+            a: i32,
+            a_b: i32,
+        }
+
+        let expected = json!({ "A": { "": "1", "B": "2" } });
+        assert_eq!(to_nested_map(&Prefix { a: 1, a_b: 2 }).unwrap(), expected);
     }
 
     #[test]
-    fn test_option() {
+    fn test_to_vec() {
         #[derive(Serialize)]
         struct Test {
             int32: i32,
-            option_int32: Option<i32>,
+            string: String,
         }
 
         let test = Test {
             int32: 1,
-            option_int32: Some(1),
+            string: String::from("é"),
         };
-        let expected = "INT32=1\nOPTION_INT32=1\n";
+        assert_eq!(
+            to_vec(&test).unwrap(),
+            to_string(&test).unwrap().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_to_writer() {
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("s"),
+        };
+        let mut output = Vec::new();
+        to_writer(&mut output, &test).unwrap();
+        assert_eq!(output, to_string(&test).unwrap().into_bytes());
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_to_writer_gzip() {
+        use super::to_writer_gzip;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        #[derive(Serialize)]
+        struct Test {
+            int32: i32,
+            string: String,
+        }
+
+        let test = Test {
+            int32: 1,
+            string: String::from("s"),
+        };
+        let mut compressed = Vec::new();
+        to_writer_gzip(&mut compressed, &test).unwrap();
+
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, to_string(&test).unwrap());
+    }
+
+    #[test]
+    fn test_option_empty_string() {
+        use serde_derive::Deserialize;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Test {
+            string: Option<String>,
+        }
+
+        // An empty string is still a value, only `None` is omitted.
+        let test = Test {
+            string: Some(String::new()),
+        };
+        let output = to_string(&test).unwrap();
+        assert_eq!(output, "STRING=\"\"\n");
+        assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+
+        let test = Test { string: None };
+        let output = to_string(&test).unwrap();
+        assert_eq!(output, "");
+        assert_eq!(crate::de::from_str::<Test>(&output).unwrap(), test);
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Serialize)]
+        enum E {
+            Unit,
+            Newtype(u32),
+            Tuple(u32, u32),
+            Struct { a: u32 },
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            status: E,
+        }
+
+        let u = Test { status: E::Unit };
+        let expected = "STATUS=\"Unit\"\n";
+        assert_eq!(to_string(&u).unwrap(), expected);
+
+        let n = Test {
+            status: E::Newtype(1),
+        };
+        let expected = "STATUS_NEWTYPE=1\n";
+        assert_eq!(to_string(&n).unwrap(), expected);
+
+        let t = Test {
+            status: E::Tuple(1, 2),
+        };
+        let expected = "STATUS_TUPLE_0=1\nSTATUS_TUPLE_1=2\n";
+        assert_eq!(to_string(&t).unwrap(), expected);
+
+        let s = Test {
+            status: E::Struct { a: 1 },
+        };
+        let expected = "STATUS_STRUCT_A=1\n";
+        assert_eq!(to_string(&s).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_tuple_variant() {
+        #[derive(Serialize)]
+        enum Shape {
+            Point(i32, i32),
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            key: Shape,
+            int32: i32,
+        }
+
+        let test = Test {
+            key: Shape::Point(1, 2),
+            int32: 3,
+        };
+        let expected = "KEY_POINT_0=1\nKEY_POINT_1=2\nINT32=3\n";
         assert_eq!(to_string(&test).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_empty_struct_variant() {
+        #[derive(Serialize)]
+        enum E {
+            Empty {},
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            status: E,
+            int32: i32,
+        }
 
         let test = Test {
+            status: E::Empty {},
             int32: 1,
-            option_int32: None,
         };
-        let expected = "INT32=1\n";
+        let expected = "STATUS=\"Empty\"\nINT32=1\n";
         assert_eq!(to_string(&test).unwrap(), expected);
     }
 
-    // #[test]
-    // fn test_enum() {
-    //     #[derive(Serialize)]
-    //     enum E {
-    //         Unit,
-    //         Newtype(u32),
-    //         Tuple(u32, u32),
-    //         Struct { a: u32 },
-    //     }
-
-    //     let u = E::Unit;
-    //     let expected = r#""Unit""#;
-    //     assert_eq!(to_string(&u).unwrap(), expected);
-
-    //     let n = E::Newtype(1);
-    //     let expected = r#"{"Newtype":1}"#;
-    //     assert_eq!(to_string(&n).unwrap(), expected);
-
-    //     let t = E::Tuple(1, 2);
-    //     let expected = r#"{"Tuple":[1,2]}"#;
-    //     assert_eq!(to_string(&t).unwrap(), expected);
-
-    //     let s = E::Struct { a: 1 };
-    //     let expected = r#"{"Struct":{"a":1}}"#;
-    //     assert_eq!(to_string(&s).unwrap(), expected);
-    // }
+    #[test]
+    fn test_internally_tagged_enum() {
+        #[derive(Serialize)]
+        struct Inner {
+            b: u32,
+        }
+
+        #[derive(Serialize)]
+        #[serde(tag = "type")]
+        enum E {
+            Unit,
+            Newtype(Inner),
+            Struct { a: u32, seq: Vec<u32> },
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            status: E,
+            int32: i32,
+        }
+
+        let u = Test {
+            status: E::Unit,
+            int32: 1,
+        };
+        let expected = "STATUS_TYPE=\"Unit\"\nINT32=1\n";
+        assert_eq!(to_string(&u).unwrap(), expected);
+
+        let n = Test {
+            status: E::Newtype(Inner { b: 2 }),
+            int32: 1,
+        };
+        let expected = "STATUS_TYPE=\"Newtype\"\nSTATUS_B=2\nINT32=1\n";
+        assert_eq!(to_string(&n).unwrap(), expected);
+
+        let s = Test {
+            status: E::Struct {
+                a: 2,
+                seq: vec![3, 4],
+            },
+            int32: 1,
+        };
+        let expected =
+            "STATUS_TYPE=\"Struct\"\nSTATUS_A=2\nSTATUS_SEQ='3,4'\nINT32=1\n";
+        assert_eq!(to_string(&s).unwrap(), expected);
+
+        // At the top level, the tag is a variable of its own.
+        let expected = "TYPE=\"Struct\"\nA=2\nSEQ='3,4'\n";
+        assert_eq!(to_string(&s.status).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_adjacently_tagged_enum() {
+        #[derive(Serialize)]
+        #[serde(tag = "t", content = "c")]
+        enum E {
+            Unit,
+            Newtype(u32),
+            Tuple(u32, u32),
+            Struct { a: u32 },
+        }
+
+        #[derive(Serialize)]
+        struct Test {
+            status: E,
+            int32: i32,
+        }
+
+        let u = Test {
+            status: E::Unit,
+            int32: 1,
+        };
+        let expected = "STATUS_T=\"Unit\"\nINT32=1\n";
+        assert_eq!(to_string(&u).unwrap(), expected);
+
+        let n = Test {
+            status: E::Newtype(2),
+            int32: 1,
+        };
+        let expected = "STATUS_T=\"Newtype\"\nSTATUS_C=2\nINT32=1\n";
+        assert_eq!(to_string(&n).unwrap(), expected);
+
+        let t = Test {
+            status: E::Tuple(2, 3),
+            int32: 1,
+        };
+        let expected = "STATUS_T=\"Tuple\"\nSTATUS_C='2,3'\nINT32=1\n";
+        assert_eq!(to_string(&t).unwrap(), expected);
+
+        let s = Test {
+            status: E::Struct { a: 2 },
+            int32: 1,
+        };
+        let expected = "STATUS_T=\"Struct\"\nSTATUS_C_A=2\nINT32=1\n";
+        assert_eq!(to_string(&s).unwrap(), expected);
+    }
 }